@@ -0,0 +1,91 @@
+//! Benchmarks for the hashing that dominates `anisort`'s runtime.
+//!
+//! The multi-GB file is only benchmarked if `ANIDB_BENCH_LARGE` is set, since just writing
+//! it takes a while.
+
+#[macro_use]
+extern crate criterion;
+extern crate anidb;
+extern crate crypto;
+
+use anidb::ed2k::{hash_files, Ed2kHash, HashOptions};
+use anidb::md4::Md4;
+use criterion::{Benchmark, Criterion, Throughput};
+use crypto::digest::Digest;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// The ed2k block size.
+const BLOCKSIZE: u64 = 9500 * 1024;
+
+/// Write a file of the given size, with non-constant contents, unless it's already there.
+fn bench_file(name: &str, size: u64) -> PathBuf {
+    let dir = env::temp_dir().join("anidb-rs-bench");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    if fs::metadata(&path)
+        .map(|m| m.len() == size)
+        .unwrap_or(false)
+    {
+        return path;
+    }
+    let chunk: Vec<u8> = (0..1024 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+    let mut file = fs::File::create(&path).unwrap();
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(chunk.len() as u64);
+        file.write_all(&chunk[..n as usize]).unwrap();
+        left -= n;
+    }
+    path
+}
+
+fn bench_md4(c: &mut Criterion) {
+    let block = [0x5a; 64];
+    c.bench(
+        "md4",
+        Benchmark::new("process_block", move |b| {
+            let mut md4 = Md4::new();
+            b.iter(|| md4.input(&block))
+        })
+        .throughput(Throughput::Bytes(64)),
+    );
+}
+
+fn bench_from_file(c: &mut Criterion) {
+    let mut sizes = vec![("small", 1024 * 1024), ("block_multiple", 2 * BLOCKSIZE)];
+    if env::var_os("ANIDB_BENCH_LARGE").is_some() {
+        sizes.push(("large", 2 * 1024 * 1024 * 1024));
+    }
+    for (name, size) in sizes {
+        let path = bench_file(&format!("{}.bin", name), size);
+        c.bench(
+            "ed2k_from_file",
+            Benchmark::new(name, move |b| {
+                b.iter(|| Ed2kHash::from_file(&path).unwrap())
+            })
+            .sample_size(10)
+            .throughput(Throughput::Bytes(size as u32)),
+        );
+    }
+}
+
+fn bench_hash_files(c: &mut Criterion) {
+    let paths: Vec<PathBuf> = (0..8)
+        .map(|i| bench_file(&format!("parallel-{}.bin", i), BLOCKSIZE + 1))
+        .collect();
+    let size = paths.len() as u64 * (BLOCKSIZE + 1);
+    c.bench(
+        "ed2k_hash_files",
+        Benchmark::new("8_files", move |b| {
+            b.iter(|| hash_files(paths.clone(), &HashOptions::default(), None))
+        })
+        .sample_size(10)
+        .throughput(Throughput::Bytes(size as u32)),
+    );
+}
+
+criterion_group!(benches, bench_md4, bench_from_file, bench_hash_files);
+criterion_main!(benches);
@@ -1,6 +1,7 @@
 extern crate anidb;
 
 use anidb::{Anidb, Result};
+use std::env;
 
 //
 // ----- YOU NEED TO CHANGE THIS FOR THE EXAMPLE TO WORK <----
@@ -10,7 +11,8 @@ static USERNAME: &'static str = "user";
 static PASSWORD: &'static str = "pass";
 
 fn login_logout() -> Result<()> {
-    let mut db = Anidb::new(("api.anidb.net", 9000))?;
+    let cache_dir = env::temp_dir().join("anidb-rs-example");
+    let mut db = Anidb::new(("api.anidb.net", 9000), &cache_dir)?;
     db.login(USERNAME, PASSWORD)?;
     db.logout()?;
     println!("Evenything went ok!");
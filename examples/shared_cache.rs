@@ -0,0 +1,36 @@
+extern crate anidb;
+
+use anidb::{Anidb, Result};
+use std::env;
+
+//
+// ----- YOU NEED TO CHANGE THIS FOR THE EXAMPLE TO WORK <----
+//
+
+static USERNAME: &'static str = "user";
+static PASSWORD: &'static str = "pass";
+
+/// Two instances sharing a cache directory, like two processes would. The first one looks
+/// the anime up on AniDB; the second never logs in, so it can only answer from the cache.
+fn shared_cache() -> Result<()> {
+    let cache_dir = env::temp_dir().join("anidb-rs-example");
+
+    let mut first = Anidb::new(("api.anidb.net", 9000), &cache_dir)?;
+    first.login(USERNAME, PASSWORD)?;
+    let anime = first.anime_from_aid(1)?;
+    println!("First instance fetched {}", anime.romaji_name);
+
+    // The first instance is still open, so this also checks the cache isn't locked by it.
+    let mut second = Anidb::new(("api.anidb.net", 9000), &cache_dir)?;
+    let cached = second.anime_from_aid(1)?;
+    println!("Second instance found {} in the cache", cached.romaji_name);
+
+    first.logout()?;
+    Ok(())
+}
+
+fn main() {
+    shared_cache().unwrap_or_else(|e| {
+        println!("Failed to share the cache {:?}", e);
+    });
+}
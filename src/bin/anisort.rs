@@ -1,33 +1,270 @@
-use std::collections::BTreeSet;
-use std::env;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs;
-use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+extern crate argparse;
+use argparse::{ArgumentParser, Collect, List, Store, StoreOption, StoreTrue};
 
 extern crate walkdir;
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 extern crate anidb;
-use anidb::ed2k::Ed2kHash;
-use anidb::{Anidb, AnidbError, File};
+use anidb::ed2k::{hash_files, hash_files_to, Ed2kHash, HashOptions};
+use anidb::{Anidb, AnidbError, File, MylistState};
 
 extern crate app_dirs;
 use app_dirs::*;
 
+extern crate dirs;
+
 extern crate ini;
 use ini::Ini;
 
+#[macro_use]
+extern crate serde_json;
+
 // Config data:
 const APP_INFO: AppInfo = AppInfo {
     name: "anisort",
     author: "Baughn",
 };
 
+/// What to do when a hash matches several AniDB files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MultiplePolicy {
+    /// Leave the file where it is.
+    Skip,
+    /// Use the first candidate AniDB returns.
+    First,
+    /// Ask the user which candidate to use.
+    Interactive,
+}
+
+impl FromStr for MultiplePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MultiplePolicy, String> {
+        match s {
+            "skip" => Ok(MultiplePolicy::Skip),
+            "first" => Ok(MultiplePolicy::First),
+            "interactive" => Ok(MultiplePolicy::Interactive),
+            _ => Err(format!(
+                "Unknown policy {:?}; expected skip, first or interactive",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do when the destination already holds another release of the same episode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VersionPolicy {
+    /// Replace whatever is there.
+    Overwrite,
+    /// Keep the higher version at the destination, and move the lower one aside.
+    PreferNewer,
+}
+
+impl FromStr for VersionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VersionPolicy, String> {
+        match s {
+            "overwrite" => Ok(VersionPolicy::Overwrite),
+            "prefer-newer" => Ok(VersionPolicy::PreferNewer),
+            _ => Err(format!(
+                "Unknown policy {:?}; expected overwrite or prefer-newer",
+                s
+            )),
+        }
+    }
+}
+
+/// How a file gets to its destination.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkMode {
+    /// Rename it, or copy and delete it if that's not possible.
+    Move,
+    /// Leave the original, and copy it.
+    Copy,
+    /// Leave the original, and hard link to it. Only works within one filesystem.
+    Hard,
+    /// Leave the original, and symlink to it.
+    Soft,
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LinkMode, String> {
+        match s {
+            "move" => Ok(LinkMode::Move),
+            "copy" => Ok(LinkMode::Copy),
+            "hard" => Ok(LinkMode::Hard),
+            "soft" => Ok(LinkMode::Soft),
+            _ => Err(format!(
+                "Unknown link mode {:?}; expected move, copy, hard or soft",
+                s
+            )),
+        }
+    }
+}
+
+/// How AniDB's names are turned into file names.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Naming {
+    /// Keep spaces, instead of turning them into `_`.
+    preserve_spaces: bool,
+    /// Only produce names that are valid on Windows too, e.g. for a shared drive. Always on
+    /// when running on Windows.
+    portable: bool,
+}
+
+/// Characters Windows doesn't allow in file names.
+const WINDOWS_ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, with or without an extension.
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Naming {
+    /// Turn a name into a single path component.
+    fn clean(&self, raw: &str) -> String {
+        let raw = if self.preserve_spaces {
+            raw.to_owned()
+        } else {
+            raw.replace(" ", "_")
+        };
+        if !(self.portable || cfg!(windows)) {
+            return raw.replace("/", "|");
+        }
+        let mut name: String = raw
+            .chars()
+            .map(|c| {
+                if c.is_control() || WINDOWS_ILLEGAL.contains(&c) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect();
+        // Windows silently drops trailing dots and spaces, so the name wouldn't round-trip.
+        let trimmed = name.trim_end_matches(&['.', ' '][..]).len();
+        name.truncate(trimmed);
+        let stem = name.split('.').next().unwrap_or("").to_uppercase();
+        if name.is_empty() || WINDOWS_RESERVED.contains(&stem.as_str()) {
+            name.insert(0, '_');
+        }
+        name
+    }
+}
+
 struct ConfigData {
     user: String,
     password: String,
     target: PathBuf,
+    multiple: MultiplePolicy,
+    versions: VersionPolicy,
+    mylist_state: Option<MylistState>,
+    naming: Naming,
+}
+
+/// Picks the destination for a file, given what AniDB says about it and where it is now.
+/// `None` if AniDB doesn't know enough about the file to name it.
+type Namer = dyn Fn(&File, &Path) -> Option<PathBuf>;
+
+/// Everything that controls how files are sorted.
+struct SortOptions {
+    mode_noop: bool,
+    /// Don't print what happens to each file, because stdout is used for `--json`.
+    quiet: bool,
+    multiple: MultiplePolicy,
+    versions: VersionPolicy,
+    /// Whether files are moved to their destination, or copied or linked there.
+    link: LinkMode,
+    /// Defaults to `build_path`, into the configured target directory.
+    namer: Box<Namer>,
+    /// Fetch fresh data from AniDB for every file, instead of using the cache.
+    refresh_all: bool,
+    /// If set, matched files are added to mylist in this state.
+    mylist_state: Option<MylistState>,
+    /// Where to write the per-file audit log, in addition to logging it.
+    audit_log: Option<fs::File>,
+    /// Destinations chosen so far this run, and the files going there.
+    destinations: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+impl SortOptions {
+    /// Tell the user what's happening to a file, unless they asked for quiet.
+    fn say(&self, message: &str) {
+        if !self.quiet {
+            println!("{}", message);
+        }
+    }
+}
+
+/// What happened to a file, and why, for the audit log.
+struct Decision {
+    fid: Option<u32>,
+    aid: Option<u32>,
+    series: Option<String>,
+    destination: Option<PathBuf>,
+    /// "moved", "would move", "skipped" or "error". Copies and links count as moves, with
+    /// the link mode as the reason.
+    action: &'static str,
+    reason: String,
+}
+
+impl Decision {
+    fn new(action: &'static str, reason: &str) -> Decision {
+        Decision {
+            fid: None,
+            aid: None,
+            series: None,
+            destination: None,
+            action,
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+/// Record a decision as one greppable line.
+fn audit(options: &SortOptions, hashdata: &HashData, decision: &Decision) {
+    let mut line = decision.action.to_owned();
+    if !decision.reason.is_empty() {
+        line.push_str(&format!(": {}", decision.reason));
+    }
+    line.push_str(&format!(" path={:?}", hashdata.filename));
+    if let Ok(ref hash) = hashdata.hash {
+        line.push_str(&format!(" hash={}", hash.hex));
+    }
+    if let Some(fid) = decision.fid {
+        line.push_str(&format!(" fid={}", fid));
+    }
+    if let Some(ref destination) = decision.destination {
+        line.push_str(&format!(" to={:?}", destination));
+    }
+    info!("{}", line);
+    if let Some(mut file) = options.audit_log.as_ref() {
+        // Files may already have moved, so carry on rather than stop halfway.
+        if let Err(err) = writeln!(file, "{}", line) {
+            warn!("Writing the audit log: {}", err);
+        }
+    }
 }
 
 impl ConfigData {
@@ -36,10 +273,16 @@ impl ConfigData {
         ini.with_section(Some("User"))
             .set("username", "<USERNAME>")
             .set("password", "<PASSWORD>");
-        ini.with_section(Some("Target directories")).set(
-            "target",
-            env::home_dir().unwrap().join("Anime").to_string_lossy(),
-        );
+        // Without a home directory there's no sensible default, so leave it to the user.
+        let target = match dirs::home_dir() {
+            Some(home) => home.join("Anime").to_string_lossy().into_owned(),
+            None => "<TARGET>".to_owned(),
+        };
+        ini.with_section(Some("Target directories"))
+            .set("target", target);
+        ini.with_section(Some("Naming"))
+            .set("preserve_spaces", "false")
+            .set("portable", "false");
         fs::create_dir_all(file.parent().unwrap()).unwrap();
         ini.write_to_file(file).expect("Failed to write ini file!");
         panic!("Ini file created. Fill in the template in {:?}", file);
@@ -52,14 +295,111 @@ impl ConfigData {
         let user = user_section.get("username")?;
         let password = user_section.get("password")?;
         let target = dirs.get("target")?;
+        let multiple = match ini.get_from(Some("Sorting"), "multiple") {
+            Some(policy) => policy
+                .parse()
+                .expect("Invalid multiple-file policy in config"),
+            None => MultiplePolicy::Skip,
+        };
+        let versions = match ini.get_from(Some("Sorting"), "versions") {
+            Some(policy) => policy.parse().expect("Invalid version policy in config"),
+            None => VersionPolicy::Overwrite,
+        };
+        let mylist_state = ini.get_from(Some("Mylist"), "default_state").map(|state| {
+            state
+                .parse()
+                .expect("Invalid mylist default_state in config")
+        });
+        let naming = Naming {
+            preserve_spaces: config_flag(&ini, "preserve_spaces"),
+            portable: config_flag(&ini, "portable"),
+        };
         return Some(ConfigData {
             user: user.to_string(),
             password: password.to_string(),
             target: PathBuf::from(target),
+            multiple,
+            versions,
+            mylist_state,
+            naming,
         });
     }
 }
 
+/// A true/false setting in the Naming section, false if it's missing.
+fn config_flag(ini: &Ini, key: &str) -> bool {
+    match ini.get_from(Some("Naming"), key) {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid Naming {} in config; expected true or false", key)),
+        None => false,
+    }
+}
+
+/// File in the cache dir recording when the last completed run started.
+const BOOKMARK_FILE: &str = "last_run";
+
+fn read_bookmark(cache_dir: &Path) -> Option<SystemTime> {
+    let contents = fs::read_to_string(cache_dir.join(BOOKMARK_FILE)).ok()?;
+    let secs = contents.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn write_bookmark(cache_dir: &Path, time: SystemTime) {
+    let secs = time.duration_since(UNIX_EPOCH).expect("time").as_secs();
+    fs::write(cache_dir.join(BOOKMARK_FILE), format!("{}\n", secs))
+        .expect("Failed to write bookmark");
+}
+
+/// Files whose mtime can't be read are assumed to have been modified.
+fn modified_since(path: &Path, since: SystemTime) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map(|mtime| mtime >= since)
+        .unwrap_or(true)
+}
+
+/// Match a name against a glob, where `*` matches any run of characters and `?` any one.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Whether a directory is excluded. Patterns with a `/` are matched against the whole path,
+/// and others against the directory's name only, so "junk" skips every directory called junk.
+fn excluded(entry: &DirEntry, exclude: &[String]) -> bool {
+    if !entry.file_type().is_dir() {
+        return false;
+    }
+    let path: Vec<char> = entry.path().to_string_lossy().chars().collect();
+    let name: Vec<char> = entry.file_name().to_string_lossy().chars().collect();
+    exclude.iter().any(|pattern| {
+        let pattern: Vec<char> = pattern.trim_end_matches('/').chars().collect();
+        if pattern.contains(&'/') {
+            glob_match(&pattern, &path)
+        } else {
+            glob_match(&pattern, &name)
+        }
+    })
+}
+
+/// Every file under `dir`. Excluded directories aren't descended into at all.
+fn walk_dir<'a>(dir: &str, exclude: &'a [String]) -> impl Iterator<Item = PathBuf> + 'a {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(move |entry| !excluded(entry, exclude))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.path().to_path_buf())
+}
+
 /// All the data we could ever want about hashed files...
 #[derive(Debug)]
 struct HashData {
@@ -67,35 +407,71 @@ struct HashData {
     hash: Result<Ed2kHash, AnidbError>,
 }
 
-fn hash(filename: PathBuf) -> HashData {
-    let hash = Ed2kHash::from_file(&filename);
-    HashData { filename, hash }
-}
+/// Hash the files, reusing and filling the hash cache. The database is only locked to look
+/// hashes up and store them, not while hashing, so lookups can go on meanwhile.
+fn hash_all(db: &Arc<Mutex<Anidb>>, files: Vec<PathBuf>) -> Vec<HashData> {
+    let mut results = Vec::with_capacity(files.len());
+    let mut uncached = Vec::new();
+    {
+        let db = db.lock().expect("lock");
+        for filename in files {
+            match Ed2kHash::from_cache(&filename, &*db.cache) {
+                Ok(Some(hash)) => results.push(Some(HashData {
+                    filename,
+                    hash: Ok(hash),
+                })),
+                _ => {
+                    uncached.push(filename);
+                    results.push(None);
+                }
+            }
+        }
+    }
 
-fn clean(raw: &String) -> String {
-    return raw.replace(" ", "_").replace("/", "|");
+    let hashed = hash_files(uncached, &HashOptions::default(), None);
+    {
+        let db = db.lock().expect("lock");
+        for (filename, hash) in &hashed {
+            if let (Ok(hash), Some((_, mtime))) = (hash, file_stamp(filename)) {
+                if let Err(err) = db.cache.put_hash(filename, mtime, hash) {
+                    warn!("Storing the hash of {:?}: {}", filename, err);
+                }
+            }
+        }
+    }
+
+    let mut hashed = hashed.into_iter();
+    results
+        .into_iter()
+        .map(|result| {
+            result.unwrap_or_else(|| {
+                let (filename, hash) = hashed.next().expect("Every uncached file is hashed");
+                HashData { filename, hash }
+            })
+        })
+        .collect()
 }
 
-fn build_path(file: &File, hashdata: &HashData, target_dir: &PathBuf) -> PathBuf {
-    let series = &file.series_romaji;
-    assert!(series != "");
+/// Needs the series name and episode number. The episode name is left out if it's unknown.
+fn build_path(file: &File, original: &Path, target_dir: &Path, naming: &Naming) -> Option<PathBuf> {
+    let series = file.series_romaji.as_ref()?;
+    let ep_number = file.ep_number.as_ref()?;
     let mut new_name = format!("{} - ", series);
     // Episode number.
-    let ep_num_int: std::result::Result<u32, _> = file.ep_number.parse();
-    let ep_digits = std::cmp::max(format!("{}", file.total_eps).len(), 2);
+    let ep_num_int: std::result::Result<u32, _> = ep_number.parse();
+    let ep_digits = std::cmp::max(format!("{}", file.total_eps.unwrap_or(0)).len(), 2);
     if ep_num_int.is_ok() {
-        for _ in file.ep_number.len()..ep_digits {
+        for _ in ep_number.len()..ep_digits {
             new_name.push('0');
         }
     }
-    new_name.push_str(&file.ep_number);
+    new_name.push_str(ep_number);
     // Episode name.
-    let ep_name = &file.ep_name;
-    assert!(ep_name != "");
-    new_name.push_str(&format!(" - {}", ep_name));
+    if let Some(ref ep_name) = file.ep_name {
+        new_name.push_str(&format!(" - {}", ep_name));
+    }
     // Extension.
-    let ext = hashdata
-        .filename
+    let ext = original
         .extension()
         .expect("Extension")
         .to_str()
@@ -104,80 +480,787 @@ fn build_path(file: &File, hashdata: &HashData, target_dir: &PathBuf) -> PathBuf
     new_name.push_str(ext);
     // Build the final path.
     let full_path = target_dir
-        .join(clean(&file.series_romaji))
-        .join(clean(&new_name));
+        .join(naming.clean(series))
+        .join(naming.clean(&new_name));
 
-    return full_path;
+    Some(full_path)
 }
 
-fn move_file(mode_noop: bool, from: &PathBuf, to: &PathBuf) {
-    if mode_noop {
-        println!(
-            "Would move \
-             {:?} \
-             to \
-             {:?}",
-            from, to
-        );
-    } else if from == to {
-        println!("Not moving {:?}", from);
+/// Whether two paths are the same file, even if reached through different symlinks or
+/// hard links.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) if a == b => true,
+        _ => same_inode(a, b),
+    }
+}
+
+#[cfg(unix)]
+fn same_inode(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_inode(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn same_device(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+/// Without device numbers, leave it to `hard_link` to fail.
+#[cfg(not(unix))]
+fn same_device(_a: &Path, _b: &Path) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(from, to)
+}
+
+#[cfg(windows)]
+fn symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(from, to)
+}
+
+/// Make a file with `create` under a temporary name next to `to`, then rename it over `to`.
+/// Whatever was at `to` stays there until the new file is complete.
+fn replace_with<F>(to: &Path, create: F) -> io::Result<()>
+where
+    F: FnOnce(&Path) -> io::Result<()>,
+{
+    let mut name = OsString::from(".");
+    name.push(to.file_name().expect("file name"));
+    name.push(".anisort-tmp");
+    let temp = to.with_file_name(name);
+    // Left over if an earlier run was interrupted.
+    let _ = fs::remove_file(&temp);
+    let result = create(&temp).and_then(|()| fs::rename(&temp, to));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Put a file at `to` the way `mode` says. Copies and links leave `from` where it is.
+fn move_file(options: &SortOptions, from: &PathBuf, to: &PathBuf, mode: LinkMode) -> Decision {
+    let (verb, doing) = match mode {
+        LinkMode::Move => ("move", "Moving"),
+        LinkMode::Copy => ("copy", "Copying"),
+        LinkMode::Hard => ("hard link", "Hard linking"),
+        LinkMode::Soft => ("symlink", "Symlinking"),
+    };
+    if from == to || same_file(from, to) {
+        options.say(&format!("Not moving {:?}", from));
+        Decision::new("skipped", "already in place")
+    } else if options.mode_noop {
+        options.say(&format!("Would {} {:?} to {:?}", verb, from, to));
+        Decision::new("would move", if mode == LinkMode::Move { "" } else { verb })
     } else {
-        println!("Moving {:?}", from);
-        println!("    to {:?}", to);
-        fs::create_dir_all(to.parent().unwrap()).expect("create_dir_all");
-        if let Err(_) = fs::rename(from, to) {
-            fs::copy(from, to).expect("Copy");
-            fs::remove_file(from).expect("Delete old");
+        options.say(&format!("{} {:?}\n    to {:?}", doing, from, to));
+        let parent = to.parent().unwrap();
+        // Move into wherever a symlinked target directory really points, so the rename and
+        // the copy fallback both see the real filesystem.
+        let to = match fs::create_dir_all(parent).and_then(|()| fs::canonicalize(parent)) {
+            Ok(parent) => parent.join(to.file_name().unwrap()),
+            Err(err) => {
+                warn!("Failed to create {:?}: {}", parent, err);
+                return Decision::new("error", &format!("creating the directory failed: {}", err));
+            }
+        };
+        if mode == LinkMode::Hard && !same_device(from, parent) {
+            warn!(
+                "Can't hard link {:?} to {:?}: different filesystems",
+                from, to
+            );
+            return Decision::new("error", "can't hard link across filesystems");
+        }
+        // Renaming replaces whatever is at the destination, and the others should too,
+        // without losing it if they fail halfway.
+        let result = match mode {
+            LinkMode::Move => fs::rename(from, &to).or_else(|_| {
+                replace_with(&to, |temp| fs::copy(from, temp).map(|_| ()))?;
+                fs::remove_file(from)
+            }),
+            LinkMode::Copy => replace_with(&to, |temp| fs::copy(from, temp).map(|_| ())),
+            LinkMode::Hard => replace_with(&to, |temp| fs::hard_link(from, temp)),
+            // To the original's absolute path, so the link works wherever the library is.
+            LinkMode::Soft => fs::canonicalize(from)
+                .and_then(|from| replace_with(&to, |temp| symlink(&from, temp))),
+        };
+        match result {
+            Ok(()) if mode == LinkMode::Move => Decision::new("moved", ""),
+            Ok(()) => Decision::new("moved", verb),
+            Err(err) => {
+                warn!("Failed to {} {:?} to {:?}: {}", verb, from, to, err);
+                Decision::new("error", &format!("{} failed: {}", verb, err))
+            }
+        }
+    }
+}
+
+/// Pick one of several candidate files, according to the policy.
+fn resolve_multiple(
+    db: &Arc<Mutex<Anidb>>,
+    policy: MultiplePolicy,
+    hashdata: &HashData,
+    fids: Vec<u32>,
+) -> Result<File, AnidbError> {
+    match policy {
+        MultiplePolicy::Skip => Err(AnidbError::MultipleFiles(fids)),
+        // A 322 reply may list no fids at all, and then there's nothing to pick.
+        MultiplePolicy::First => match fids.first() {
+            Some(&fid) => db.lock().expect("lock").file_from_fid(fid),
+            None => Err(AnidbError::MultipleFiles(fids)),
+        },
+        MultiplePolicy::Interactive => {
+            let mut candidates = Vec::new();
+            for fid in &fids {
+                candidates.push(db.lock().expect("lock").file_from_fid(*fid)?);
+            }
+            println!("{:?} matches several files:", hashdata.filename);
+            for (i, file) in candidates.iter().enumerate() {
+                let filename = file.filename.as_ref().map_or("unknown", String::as_str);
+                println!("  [{}] {} ({})", i + 1, file, filename);
+            }
+            print!("Pick one, or press enter to skip: ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            match line.trim().parse::<usize>() {
+                Ok(n) if n >= 1 && n <= candidates.len() => Ok(candidates.swap_remove(n - 1)),
+                _ => Err(AnidbError::MultipleFiles(fids)),
+            }
+        }
+    }
+}
+
+/// Name to move an older release aside to, e.g. `Foo - 01 - Bar.v1.mkv`.
+fn aside_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_stem().expect("file_stem").to_os_string();
+    name.push(format!(".v{}", version));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+    path.with_file_name(name)
+}
+
+/// Deal with another release already sitting at the destination. Returns why the new file
+/// shouldn't be moved there, if it shouldn't.
+fn resolve_version_conflict(
+    db: &Arc<Mutex<Anidb>>,
+    options: &SortOptions,
+    file: &File,
+    from: &PathBuf,
+    to: &PathBuf,
+) -> Option<String> {
+    if options.versions == VersionPolicy::Overwrite || !to.exists() || same_file(from, to) {
+        return None;
+    }
+    let existing = {
+        let mut db = db.lock().expect("lock");
+        Ed2kHash::from_file_cached(to, &*db.cache).and_then(|hash| db.file_from_hash(&hash))
+    };
+    let existing = match existing {
+        Ok(existing) => existing,
+        Err(err) => {
+            options.say(&format!(
+                "Not moving {:?}: can't identify {:?}: {}",
+                from, to, err
+            ));
+            return Some(format!("can't identify existing file: {}", err));
+        }
+    };
+    if file.version() <= existing.version() {
+        options.say(&format!(
+            "Not moving {:?}: {:?} is already v{}",
+            from,
+            to,
+            existing.version()
+        ));
+        return Some(format!("destination is already v{}", existing.version()));
+    }
+    let aside = aside_path(to, existing.version());
+    // The old release is already in the library, so it's always moved, whatever the mode.
+    move_file(options, to, &aside, LinkMode::Move);
+    None
+}
+
+/// Remember that a file is going to `to`. Returns why it shouldn't, if another file already
+/// went there during this run.
+fn claim_destination(options: &SortOptions, from: &PathBuf, to: &PathBuf) -> Option<String> {
+    let mut destinations = options.destinations.lock().expect("lock");
+    match destinations.get(to) {
+        Some(other) if other != from => {
+            options.say(&format!(
+                "Not moving {:?}: {:?} is already going to {:?}",
+                from, other, to
+            ));
+            return Some(format!("{:?} already went there", other));
+        }
+        _ => (),
+    }
+    destinations.insert(to.clone(), from.clone());
+    None
+}
+
+/// Failing to add a file to mylist doesn't stop it from being sorted.
+fn add_to_mylist(db: &Arc<Mutex<Anidb>>, hashdata: &HashData, hash: &Ed2kHash, state: MylistState) {
+    match db.lock().expect("lock").mylist_add(hash, state, false) {
+        Ok(outcome) => info!("Mylist {:?}: {:?}", hashdata.filename, outcome),
+        Err(err) => {
+            abort_if_banned(&err);
+            warn!("Adding {:?} to mylist: {}", hashdata.filename, err)
+        }
+    }
+}
+
+/// A ban only gets longer if we keep asking, so stop the whole run. The bookmark isn't
+/// written, so the next run picks up these files again. The same goes for AniDB refusing
+/// the client itself, since no login will work until anisort is updated.
+fn abort_if_banned(err: &AnidbError) {
+    match *err {
+        AnidbError::Banned(_) => {
+            println!("{}; stopping. Try again once the ban has run out.", err);
+            process::exit(1);
+        }
+        AnidbError::ClientOutdated | AnidbError::ClientBanned(_) => {
+            println!("{}; stopping. Check for a newer anisort.", err);
+            process::exit(1);
         }
+        _ => {}
     }
 }
 
-fn search(db: &Arc<Mutex<Anidb>>, mode_noop: bool, hashdata: HashData, target_dir: &PathBuf) -> () {
-    match hashdata.hash {
+fn search(db: &Arc<Mutex<Anidb>>, options: &SortOptions, hashdata: HashData) -> Decision {
+    let decision = match hashdata.hash {
         Ok(ref hash) => {
-            let result = db.lock().expect("lock").file_from_hash(&hash);
+            let result = if options.refresh_all {
+                db.lock().expect("lock").refresh_file(hash)
+            } else if let Some(fid) = known_fid(db, &hashdata.filename) {
+                // Also saves picking again among several files with this hash.
+                db.lock().expect("lock").file_from_fid(fid)
+            } else {
+                db.lock().expect("lock").file_from_hash(hash)
+            };
+            let result = match result {
+                Err(AnidbError::MultipleFiles(fids)) => {
+                    resolve_multiple(db, options.multiple, &hashdata, fids)
+                }
+                other => other,
+            };
             match result {
                 Ok(file) => {
-                    let new_path = build_path(&file, &hashdata, target_dir);
-                    move_file(mode_noop, &hashdata.filename, &new_path);
+                    // Before the file is moved away from this path.
+                    remember_fid(db, &hashdata.filename, file.fid);
+                    let mut decision = match (options.namer)(&file, &hashdata.filename) {
+                        Some(new_path) => {
+                            let skip = claim_destination(options, &hashdata.filename, &new_path)
+                                .or_else(|| {
+                                    resolve_version_conflict(
+                                        db,
+                                        options,
+                                        &file,
+                                        &hashdata.filename,
+                                        &new_path,
+                                    )
+                                });
+                            let mut decision = match skip {
+                                None => {
+                                    move_file(options, &hashdata.filename, &new_path, options.link)
+                                }
+                                Some(reason) => Decision::new("skipped", &reason),
+                            };
+                            decision.destination = Some(new_path);
+                            decision
+                        }
+                        None => {
+                            options.say(&format!(
+                                "Not moving {:?}: AniDB doesn't know its series or episode",
+                                hashdata.filename
+                            ));
+                            Decision::new("skipped", "series or episode unknown")
+                        }
+                    };
+                    if let Some(state) = options.mylist_state {
+                        if !options.mode_noop {
+                            add_to_mylist(db, &hashdata, hash, state);
+                        }
+                    }
+                    decision.fid = Some(file.fid);
+                    decision.aid = Some(file.aid);
+                    decision.series = file.series_romaji.clone();
+                    decision
+                }
+                Err(err @ AnidbError::MultipleFiles(_)) => {
+                    warn!("Looking up {:?}: {}", hashdata.filename, err);
+                    Decision::new("skipped", &err.to_string())
                 }
                 Err(err) => {
-                    println!("Looking up {:?}: {}", hashdata.filename, err);
+                    abort_if_banned(&err);
+                    warn!("Looking up {:?}: {}", hashdata.filename, err);
+                    Decision::new("error", &err.to_string())
                 }
-            };
+            }
         }
-        Err(err) => {
-            println!("Looking up {:?}: {}", hashdata.filename, err);
+        Err(ref err) => {
+            warn!("Looking up {:?}: {}", hashdata.filename, err);
+            Decision::new("error", &err.to_string())
         }
     };
+    audit(options, &hashdata, &decision);
+    let in_place = decision.action == "moved" || decision.reason == "already in place";
+    if in_place && !options.mode_noop {
+        if let (Ok(hash), Some(destination)) = (&hashdata.hash, &decision.destination) {
+            mark_sorted(db, destination, hash);
+        }
+    }
+    decision
+}
+
+/// Hash the files on a separate thread, while this thread looks each one up as soon as its
+/// hash is ready. That way hashing never waits for the rate limit, and the lookups only
+/// wait for hashing if they catch up with it.
+///
+/// Files are looked up in the order their hashes come in, but the decisions are returned
+/// in the order of `files`.
+fn hash_and_search(
+    db: &Arc<Mutex<Anidb>>,
+    options: &SortOptions,
+    files: Vec<PathBuf>,
+) -> Vec<(PathBuf, Decision)> {
+    let mut cached = Vec::new();
+    let mut uncached = Vec::new();
+    {
+        let db = db.lock().expect("lock");
+        for (i, filename) in files.into_iter().enumerate() {
+            match Ed2kHash::from_cache(&filename, &*db.cache) {
+                Ok(Some(hash)) => cached.push((
+                    i,
+                    HashData {
+                        filename,
+                        hash: Ok(hash),
+                    },
+                )),
+                _ => uncached.push((i, filename)),
+            }
+        }
+    }
+
+    let (indices, paths): (Vec<usize>, Vec<PathBuf>) = uncached.into_iter().unzip();
+    let (sender, receiver) = mpsc::channel();
+    let hasher = thread::spawn(move || hash_files_to(paths, &HashOptions::default(), sender));
+    let hashed = receiver
+        .into_iter()
+        .map(|(i, filename, hash)| (indices[i], HashData { filename, hash }, true));
+
+    let mut decisions = Vec::new();
+    let cached = cached.into_iter().map(|(i, hashdata)| (i, hashdata, false));
+    for (i, hashdata, fresh) in cached.chain(hashed) {
+        // Store a new hash before the file is moved away from this path.
+        if let (true, Ok(hash), Some((_, mtime))) =
+            (fresh, &hashdata.hash, file_stamp(&hashdata.filename))
+        {
+            let db = db.lock().expect("lock");
+            if let Err(err) = db.cache.put_hash(&hashdata.filename, mtime, hash) {
+                warn!("Storing the hash of {:?}: {}", hashdata.filename, err);
+            }
+        }
+        let filename = hashdata.filename.clone();
+        decisions.push((i, filename, search(db, options, hashdata)));
+    }
+    hasher.join().expect("Hashing thread panicked");
+
+    decisions.sort_by_key(|&(i, _, _)| i);
+    decisions
+        .into_iter()
+        .map(|(_, filename, decision)| (filename, decision))
+        .collect()
+}
+
+/// The size and mtime of a file, which together tell whether it has changed.
+fn file_stamp(path: &Path) -> Option<(u64, i64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), mtime.as_secs() as i64))
+}
+
+/// Remember that a file is in place, so later runs can skip it.
+fn mark_sorted(db: &Arc<Mutex<Anidb>>, path: &Path, hash: &Ed2kHash) {
+    if let Some((_, mtime)) = file_stamp(path) {
+        if let Err(err) = db
+            .lock()
+            .expect("lock")
+            .cache
+            .mark_sorted(path, mtime, hash)
+        {
+            warn!("Remembering {:?} as sorted: {}", path, err);
+        }
+    }
+}
+
+/// The fid an earlier run found for a file, if the file hasn't changed since.
+fn known_fid(db: &Arc<Mutex<Anidb>>, path: &Path) -> Option<u32> {
+    let (size, mtime) = file_stamp(path)?;
+    db.lock()
+        .expect("lock")
+        .cache
+        .get_fid(path, size, mtime)
+        .unwrap_or(None)
+}
+
+/// Remember which file AniDB knows a file as, for `known_fid`.
+fn remember_fid(db: &Arc<Mutex<Anidb>>, path: &Path, fid: u32) {
+    if let Some((size, mtime)) = file_stamp(path) {
+        if let Err(err) = db
+            .lock()
+            .expect("lock")
+            .cache
+            .put_fid(path, size, mtime, fid)
+        {
+            warn!("Remembering the fid of {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Whether a file was sorted by an earlier run, and hasn't changed since.
+fn already_sorted(db: &Arc<Mutex<Anidb>>, path: &Path) -> bool {
+    match file_stamp(path) {
+        Some((size, mtime)) => db
+            .lock()
+            .expect("lock")
+            .cache
+            .is_sorted(path, size, mtime)
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Print every file that would be renamed, as current path -> proposed path.
+fn print_diff(decisions: &[(PathBuf, Decision)]) {
+    let mut changes = 0;
+    for (path, decision) in decisions {
+        if decision.action != "would move" {
+            continue;
+        }
+        if let Some(ref destination) = decision.destination {
+            println!("- {}", path.display());
+            println!("+ {}", destination.display());
+            changes += 1;
+        }
+    }
+    println!("{} file(s) would be moved", changes);
+}
+
+/// Print every file that was or would be moved, as a JSON array.
+fn print_json(decisions: &[(PathBuf, Decision)]) {
+    let moves: Vec<serde_json::Value> = decisions
+        .iter()
+        .filter(|(_, decision)| decision.action == "moved" || decision.action == "would move")
+        .map(|(path, decision)| {
+            json!({
+                "from": path.to_string_lossy(),
+                "to": decision.destination.as_ref().map(|to| to.to_string_lossy()),
+                "fid": decision.fid,
+                "aid": decision.aid,
+                "series": decision.series,
+            })
+        })
+        .collect();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&moves).expect("JSON encoding")
+    );
+}
+
+/// Files hashed and looked up per batch when indexing. Everything up to the last finished
+/// batch is in the caches, so an interrupted index loses at most this much work.
+const INDEX_BATCH: usize = 32;
+
+/// Hash and look up every file, filling the hash and API caches, without moving anything.
+///
+/// Files already in the caches are skipped over quickly, so this can be re-run to continue
+/// an interrupted index.
+fn index(db: &Arc<Mutex<Anidb>>, files: Vec<PathBuf>) {
+    let total = files.len();
+    let mut done = 0;
+    for batch in files.chunks(INDEX_BATCH) {
+        for hashdata in hash_all(db, batch.to_vec()) {
+            let result = match hashdata.hash {
+                Ok(ref hash) => {
+                    let mut db = db.lock().expect("lock");
+                    match db.file_from_hash(hash) {
+                        Err(AnidbError::MultipleFiles(fids)) => fids
+                            .into_iter()
+                            .try_for_each(|fid| db.file_from_fid(fid).map(|_| ())),
+                        // Not knowing the file is an answer too, and it's cached.
+                        Err(AnidbError::NoSuchFile) => Ok(()),
+                        other => other.map(|_| ()),
+                    }
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                abort_if_banned(&err);
+                warn!("Indexing {:?}: {}", hashdata.filename, err);
+            }
+        }
+        done += batch.len();
+        println!("Indexed {}/{} files", done, total);
+    }
 }
 
 fn main() -> () {
+    // Warnings are shown unless RUST_LOG says otherwise; RUST_LOG=info adds the audit trail.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    // Parse command line for parameters.
+    let mut mode_noop = false;
+    let mut multiple: Option<MultiplePolicy> = None;
+    let mut versions: Option<VersionPolicy> = None;
+    let mut link = LinkMode::Move;
+    let mut incremental = false;
+    let mut dry_run_diff = false;
+    let mut json = false;
+    let mut index_only = false;
+    let mut refresh_all = false;
+    let mut reverify = false;
+    let mut audit_log: Option<String> = None;
+    let mut exclude: Vec<String> = Vec::new();
+    let mut dirs: Vec<String> = Vec::new();
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Sort anime files into directories, using AniDB.");
+        ap.refer(&mut mode_noop)
+            .add_option(&["-n"], StoreTrue, "Only show what would be moved");
+        ap.refer(&mut dry_run_diff).add_option(
+            &["--dry-run-diff"],
+            StoreTrue,
+            "Like -n, but end with a list of every file that would be renamed",
+        );
+        ap.refer(&mut json).add_option(
+            &["-j", "--json"],
+            StoreTrue,
+            "Instead of describing each file, end with a JSON array of the files moved, or \
+             with -n, the files that would be moved",
+        );
+        ap.refer(&mut index_only).add_option(
+            &["--index"],
+            StoreTrue,
+            "Only hash and look up the files, filling the caches, without moving anything. \
+             Can be re-run to continue where it stopped",
+        );
+        ap.refer(&mut refresh_all).add_option(
+            &["--refresh-all"],
+            StoreTrue,
+            "Ignore cached AniDB data and look every file up again",
+        );
+        ap.refer(&mut reverify).add_option(
+            &["--reverify"],
+            StoreTrue,
+            "Also check files that an earlier run already sorted into place",
+        );
+        ap.refer(&mut multiple).add_option(
+            &["--multiple"],
+            StoreOption,
+            "What to do when a file matches several AniDB entries: skip, first or interactive",
+        );
+        ap.refer(&mut versions).add_option(
+            &["--versions"],
+            StoreOption,
+            "What to do when the destination holds another release version: overwrite or \
+             prefer-newer",
+        );
+        ap.refer(&mut link).add_option(
+            &["--link"],
+            Store,
+            "How to put files in place: move, copy, hard or soft. Hard and soft links leave \
+             the originals where they are, e.g. to keep seeding them",
+        );
+        ap.refer(&mut incremental).add_option(
+            &["--incremental", "--since-last-run"],
+            StoreTrue,
+            "Only consider files modified since the last completed run",
+        );
+        ap.refer(&mut audit_log).add_option(
+            &["--audit-log"],
+            StoreOption,
+            "Append a line per file, saying what was done with it and why, to this file",
+        );
+        ap.refer(&mut exclude).add_option(
+            &["--exclude"],
+            Collect,
+            "Don't look inside directories matching this glob, e.g. \"to-review\" or \
+             \"/media/anime/junk*\". Can be given several times",
+        );
+        ap.refer(&mut dirs)
+            .add_argument("dirs", List, "Directories to sort");
+        ap.parse_args_or_exit();
+    }
+
     let config_dir =
         get_app_root(AppDataType::UserConfig, &APP_INFO).expect("Failed to get app dir");
     let cache_dir =
         get_app_root(AppDataType::UserCache, &APP_INFO).expect("Failed to get cache dir");
     let config =
         ConfigData::from_file(config_dir.join("config.ini")).expect("Failed to load config file");
+    let mode_noop = mode_noop || dry_run_diff;
+    let options = SortOptions {
+        mode_noop,
+        quiet: json,
+        multiple: multiple.unwrap_or(config.multiple),
+        versions: versions.unwrap_or(config.versions),
+        link,
+        namer: {
+            let target = config.target.clone();
+            let naming = config.naming;
+            Box::new(move |file: &File, original: &Path| {
+                build_path(file, original, &target, &naming)
+            })
+        },
+        refresh_all,
+        mylist_state: config.mylist_state,
+        audit_log: audit_log.map(|path| {
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("Failed to open audit log")
+        }),
+        destinations: Mutex::new(HashMap::new()),
+    };
+    let run_start = SystemTime::now();
+    // Files modified in the same second as the bookmark are rechecked, to be safe.
+    let since = if incremental {
+        read_bookmark(&cache_dir)
+    } else {
+        None
+    };
 
-    // Parse command line for parameters.
-    let mut args: BTreeSet<String> = BTreeSet::from_iter(env::args().skip(1));
-    let mode_noop = args.remove("-n");
+    if refresh_all {
+        // On stderr, so it doesn't end up in --json output.
+        eprintln!(
+            "WARNING: --refresh-all looks up every file on AniDB again. With the rate limit, \
+             that's at least 4 seconds per file, and AniDB may ban clients that do this often."
+        );
+    }
 
     // Login to AniDB.
     let db = Arc::new(Mutex::new(
         Anidb::new(("api.anidb.net", 9000), &cache_dir).unwrap(),
     ));
-    db.lock()
-        .unwrap()
-        .login(&config.user, &config.password)
-        .expect("Failed AniDB login");
+    {
+        let mut db = db.lock().unwrap();
+        db.login(&config.user, &config.password)
+            .expect("Failed AniDB login");
+    }
 
     // List all files, hash and send them...
-    args.iter()
-        .flat_map(|ref dirname| WalkDir::new(dirname))
-        .filter_map(|entry| entry.map(Some).unwrap_or(None))
-        .filter(|entry| entry.file_type().is_file())
-        .map(|file| hash(file.path().to_path_buf()))
-        .for_each(|hashdata| search(&db, mode_noop, hashdata, &config.target));
+    let files: Vec<PathBuf> = dirs
+        .iter()
+        .flat_map(|dirname| walk_dir(dirname, &exclude))
+        .filter(|path| since.map_or(true, |since| modified_since(path, since)))
+        .collect();
+    let files = if reverify || index_only {
+        files
+    } else {
+        let found = files.len();
+        let files: Vec<PathBuf> = files
+            .into_iter()
+            .filter(|path| !already_sorted(&db, path))
+            .collect();
+        if files.len() < found {
+            options.say(&format!(
+                "Skipping {} files already sorted by an earlier run; use --reverify to check them",
+                found - files.len()
+            ));
+        }
+        files
+    };
+    if index_only {
+        index(&db, files);
+        return;
+    }
+    let decisions = hash_and_search(&db, &options, files);
+    if json {
+        print_json(&decisions);
+    } else if dry_run_diff {
+        print_diff(&decisions);
+    }
+
+    // Only reached if the run wasn't interrupted. A dry run doesn't count.
+    if !mode_noop {
+        write_bookmark(&cache_dir, run_start);
+    }
+}
+
+#[cfg(test)]
+mod test_naming {
+    use super::*;
+
+    const PORTABLE: Naming = Naming {
+        preserve_spaces: false,
+        portable: true,
+    };
+
+    #[test]
+    fn test_clean_illegal_characters() {
+        assert_eq!(PORTABLE.clean("Fate/Zero: Why?"), "Fate_Zero__Why_");
+        assert_eq!(PORTABLE.clean("a<b>c\"d\\e|f*g"), "a_b_c_d_e_f_g");
+        assert_eq!(PORTABLE.clean("tab\there\nnewline"), "tab_here_newline");
+    }
+
+    #[test]
+    fn test_clean_trailing_dots_and_spaces() {
+        let naming = Naming {
+            preserve_spaces: true,
+            portable: true,
+        };
+        assert_eq!(naming.clean("To Be Continued... "), "To Be Continued");
+        assert_eq!(PORTABLE.clean("Ends with a dot."), "Ends_with_a_dot");
+    }
+
+    #[test]
+    fn test_clean_reserved_names() {
+        assert_eq!(PORTABLE.clean("CON.mkv"), "_CON.mkv");
+        assert_eq!(PORTABLE.clean("nul"), "_nul");
+        assert_eq!(PORTABLE.clean("Com1.part.avi"), "_Com1.part.avi");
+        assert_eq!(PORTABLE.clean("CONAN.mkv"), "CONAN.mkv");
+    }
+
+    #[test]
+    fn test_clean_empty() {
+        assert_eq!(PORTABLE.clean(""), "_");
+        assert_eq!(PORTABLE.clean("..."), "_");
+    }
+
+    #[test]
+    fn test_clean_spaces() {
+        let preserved = Naming {
+            preserve_spaces: true,
+            portable: true,
+        };
+        assert_eq!(PORTABLE.clean("Cowboy Bebop"), "Cowboy_Bebop");
+        assert_eq!(preserved.clean("Cowboy Bebop"), "Cowboy Bebop");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn test_clean_not_portable() {
+        let naming = Naming::default();
+        assert_eq!(naming.clean("Fate/Zero: Why?"), "Fate|Zero:_Why?");
+        assert_eq!(naming.clean("CON.mkv"), "CON.mkv");
+    }
 }
@@ -0,0 +1,237 @@
+use errors::{AnidbError, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use {Anidb, Cache, DEFAULT_CLIENT_NAME, DEFAULT_CLIENT_VERSION};
+
+/// Sets up an `Anidb` in one go, instead of changing its fields after creating it.
+///
+/// ```ignore
+/// let mut db = AnidbBuilder::new()
+///     .cache_dir(cache_dir)
+///     .client("myclient", 2)
+///     .login("user", "pass")
+///     .compression(true)
+///     .connect()?;
+/// ```
+#[derive(Clone)]
+pub struct AnidbBuilder {
+    host: String,
+    port: u16,
+    bind: Option<SocketAddr>,
+    cache_dir: Option<PathBuf>,
+    cache_ttl: Option<Duration>,
+    ratelimit: Option<Duration>,
+    jitter: Duration,
+    adaptive_ratelimit: bool,
+    timeout: Option<Duration>,
+    max_retries: Option<u32>,
+    client_name: String,
+    client_version: u32,
+    compression: bool,
+    credentials: Option<(String, String)>,
+    persist_session: bool,
+    api_key: Option<String>,
+}
+
+impl AnidbBuilder {
+    /// Starts out talking to the real AniDB, with the library's defaults.
+    pub fn new() -> AnidbBuilder {
+        AnidbBuilder {
+            host: "api.anidb.net".to_owned(),
+            port: 9000,
+            bind: None,
+            cache_dir: None,
+            cache_ttl: None,
+            ratelimit: None,
+            jitter: Duration::from_secs(0),
+            adaptive_ratelimit: false,
+            timeout: None,
+            max_retries: None,
+            client_name: DEFAULT_CLIENT_NAME.to_owned(),
+            client_version: DEFAULT_CLIENT_VERSION,
+            compression: false,
+            credentials: None,
+            persist_session: false,
+            api_key: None,
+        }
+    }
+
+    pub fn address(mut self, host: &str, port: u16) -> AnidbBuilder {
+        self.host = host.to_owned();
+        self.port = port;
+        self
+    }
+
+    /// See `Anidb::with_bind_address`.
+    pub fn bind_address(mut self, bind: SocketAddr) -> AnidbBuilder {
+        self.bind = Some(bind);
+        self
+    }
+
+    /// Where to keep the cache. Required.
+    pub fn cache_dir(mut self, cache_dir: PathBuf) -> AnidbBuilder {
+        self.cache_dir = Some(cache_dir);
+        self
+    }
+
+    /// See `Anidb::set_cache_ttl`.
+    pub fn cache_ttl(mut self, ttl: Duration) -> AnidbBuilder {
+        self.cache_ttl = Some(ttl);
+        self
+    }
+
+    /// See `Anidb::ratelimit`. Don't lower it below the default against the real AniDB.
+    pub fn ratelimit(mut self, ratelimit: Duration) -> AnidbBuilder {
+        self.ratelimit = Some(ratelimit);
+        self
+    }
+
+    /// See `Anidb::jitter`.
+    pub fn jitter(mut self, jitter: Duration) -> AnidbBuilder {
+        self.jitter = jitter;
+        self
+    }
+
+    /// See `Anidb::adaptive_ratelimit`.
+    pub fn adaptive_ratelimit(mut self, adaptive: bool) -> AnidbBuilder {
+        self.adaptive_ratelimit = adaptive;
+        self
+    }
+
+    /// See `Anidb::set_timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> AnidbBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// See `Anidb::max_retries`.
+    pub fn max_retries(mut self, max_retries: u32) -> AnidbBuilder {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// The name and version the client is registered under on AniDB.
+    pub fn client(mut self, name: &str, version: u32) -> AnidbBuilder {
+        self.client_name = name.to_owned();
+        self.client_version = version;
+        self
+    }
+
+    /// See `Anidb::compression`.
+    pub fn compression(mut self, compression: bool) -> AnidbBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Log in as this user, when the first query needs it.
+    pub fn login(mut self, username: &str, password: &str) -> AnidbBuilder {
+        self.credentials = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    /// See `Anidb::persist_session`.
+    pub fn persist_session(mut self, persist: bool) -> AnidbBuilder {
+        self.persist_session = persist;
+        self
+    }
+
+    /// Encrypt the session with this API key. Needs `login`, since AniDB looks the key up
+    /// by user name.
+    pub fn encryption(mut self, api_key: &str) -> AnidbBuilder {
+        self.api_key = Some(api_key.to_owned());
+        self
+    }
+
+    /// Check the settings fit together, and create the `Anidb`.
+    ///
+    /// With `encryption`, this already talks to AniDB, to set the encryption up.
+    pub fn connect(self) -> Result<Anidb> {
+        let cache_dir = match self.cache_dir {
+            Some(ref cache_dir) => cache_dir,
+            None => return Err(AnidbError::StaticError("AnidbBuilder needs a cache_dir")),
+        };
+        if self.api_key.is_some() && self.credentials.is_none() {
+            return Err(AnidbError::StaticError(
+                "Encryption needs login credentials",
+            ));
+        }
+        if self.client_name.is_empty() {
+            return Err(AnidbError::StaticError("The client name can't be empty"));
+        }
+
+        let address = (self.host.as_str(), self.port);
+        let mut db = match self.bind {
+            Some(bind) => Anidb::with_bind_address(address, bind, Cache::new(cache_dir)?)?,
+            None => Anidb::new(address, cache_dir)?,
+        };
+        if let Some(ttl) = self.cache_ttl {
+            db.set_cache_ttl(ttl);
+        }
+        if let Some(ratelimit) = self.ratelimit {
+            db.ratelimit = ratelimit;
+        }
+        db.jitter = self.jitter;
+        db.adaptive_ratelimit = self.adaptive_ratelimit;
+        if let Some(timeout) = self.timeout {
+            db.set_timeout(timeout)?;
+        }
+        if let Some(max_retries) = self.max_retries {
+            db.max_retries = max_retries;
+        }
+        db.client_name = self.client_name.clone();
+        db.client_version = self.client_version;
+        db.compression = self.compression;
+        db.persist_session = self.persist_session;
+        if let Some((ref username, ref password)) = self.credentials {
+            db.login(username, password)?;
+        }
+        if let Some(ref api_key) = self.api_key {
+            db.enable_encryption(api_key)?;
+        }
+        Ok(db)
+    }
+}
+
+impl Default for AnidbBuilder {
+    fn default() -> AnidbBuilder {
+        AnidbBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod test_builder {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_builder_sets_fields() {
+        let db = AnidbBuilder::new()
+            .address("127.0.0.1", 9)
+            .cache_dir(env::temp_dir().join("anidb-rs-test-builder"))
+            .cache_ttl(Duration::from_secs(60))
+            .ratelimit(Duration::from_secs(5))
+            .client("myclient", 3)
+            .compression(true)
+            .max_retries(5)
+            .connect()
+            .unwrap();
+        assert_eq!(db.cache_ttl(), Duration::from_secs(60));
+        assert_eq!(db.ratelimit, Duration::from_secs(5));
+        assert_eq!(db.client_name, "myclient");
+        assert_eq!(db.client_version, 3);
+        assert!(db.compression);
+        assert_eq!(db.max_retries, 5);
+    }
+
+    #[test]
+    fn test_builder_validates() {
+        assert!(AnidbBuilder::new().connect().is_err());
+        let encryption_without_login = AnidbBuilder::new()
+            .address("127.0.0.1", 9)
+            .cache_dir(env::temp_dir().join("anidb-rs-test-builder-validates"))
+            .encryption("key")
+            .connect();
+        assert!(encryption_without_login.is_err());
+    }
+}
@@ -1,15 +1,79 @@
 extern crate rusqlite;
 
-use self::rusqlite::Connection;
-use errors::Result;
+use self::rusqlite::{Connection, ErrorCode};
+use ed2k::Ed2kHash;
+use errors::{AnidbError, Result};
 use ServerReply;
 
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Only the data of replies is cached, since the status text just repeats the code. Cached
+/// replies get this status text instead.
+const CACHED_STATUS: &str = "CACHED";
+
+/// Cached replies older than this are fetched again, by default.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Where `Anidb` keeps replies, file hashes and its session between calls. `Cache` keeps
+/// them in SQLite on disk; `MemoryCache` keeps them only for as long as it lives.
+pub trait CacheBackend: Send {
+    /// The cached reply to a query, if there's one younger than `max_age`.
+    fn get(&self, query: &str) -> Result<Option<ServerReply>>;
+    fn put(&self, query: &str, reply: &ServerReply) -> Result<()>;
+    /// Drop the cached reply for a query, if any.
+    fn invalidate(&self, query: &str) -> Result<()>;
+    /// Drop every cached reply. File hashes are kept, since they don't depend on AniDB.
+    fn clear(&self) -> Result<()>;
+    /// Drop the cached replies older than `older_than`, returning how many there were.
+    fn prune(&self, older_than: Duration) -> Result<usize>;
+    /// Cached replies older than this are treated as missing.
+    fn max_age(&self) -> Duration;
+    fn set_max_age(&mut self, max_age: Duration);
+
+    /// Get the stored hash of a file, if the size and mtime still match.
+    fn get_hash(&self, path: &Path, size: u64, mtime: i64) -> Result<Option<Ed2kHash>>;
+    /// Store the hash of a file. Any fid recorded for the file before is forgotten.
+    fn put_hash(&self, path: &Path, mtime: i64, hash: &Ed2kHash) -> Result<()>;
+    /// Get the fid AniDB gave for a file's hash, if the size and mtime still match.
+    fn get_fid(&self, path: &Path, size: u64, mtime: i64) -> Result<Option<u32>>;
+    /// Record which file AniDB knows a hashed file as, so an unchanged file can be looked
+    /// up by fid next time. Does nothing unless the file's hash is stored with this size
+    /// and mtime.
+    fn put_fid(&self, path: &Path, size: u64, mtime: i64, fid: u32) -> Result<()>;
+
+    /// Record that a file has been sorted into place. It counts as sorted until its size or
+    /// mtime changes.
+    fn mark_sorted(&self, path: &Path, mtime: i64, hash: &Ed2kHash) -> Result<()>;
+    /// Whether a file was sorted into place, and hasn't changed since.
+    fn is_sorted(&self, path: &Path, size: u64, mtime: i64) -> Result<bool>;
+
+    /// Get the session key stored for a user, if it's younger than `max_age` and was made
+    /// from the local address `local`. AniDB ties sessions to the client's address and port,
+    /// so one made from anywhere else would only be rejected.
+    fn get_session(
+        &self,
+        user: &str,
+        local: &SocketAddr,
+        max_age: Duration,
+    ) -> Result<Option<String>>;
+    /// Store a user's session key, and the local address it was made from, so another
+    /// process on that address can carry on with it.
+    fn put_session(&self, user: &str, local: &SocketAddr, session: &str) -> Result<()>;
+    /// Forget a user's session key, after logging out or AniDB rejecting it.
+    fn clear_session(&self, user: &str) -> Result<()>;
+}
 
 pub struct Cache {
     conn: Connection,
+    path: PathBuf,
+    /// Cached replies older than this are treated as missing.
+    pub max_age: Duration,
 }
 
 fn now() -> i64 {
@@ -20,9 +84,40 @@ fn now() -> i64 {
 }
 
 impl Cache {
+    /// Open the cache in `cache_dir`, creating it if needed.
+    ///
+    /// If the directory or database can't be written, e.g. on a read-only filesystem, this
+    /// warns and falls back to a cache in memory, which is lost when it's dropped.
     pub fn new(cache_dir: &PathBuf) -> Result<Cache> {
+        let path = cache_dir.join("anidb-rs.sqlite");
+        let conn = match Self::open_dir(cache_dir, &path) {
+            Err(ref err) if unwritable(err) => {
+                warn!(
+                    "Cache {:?} isn't writable ({}); using a temporary cache in memory",
+                    path, err
+                );
+                Self::init(Connection::open_in_memory()?)?
+            }
+            conn => conn?,
+        };
+        Ok(Cache {
+            conn,
+            path,
+            max_age: DEFAULT_MAX_AGE,
+        })
+    }
+
+    fn open_dir(cache_dir: &Path, path: &Path) -> Result<Connection> {
         fs::create_dir_all(cache_dir)?;
-        let conn = Connection::open(cache_dir.join("anidb-rs.sqlite"))?;
+        Self::open(path).map_err(|err| corrupt(path, err))
+    }
+
+    fn open(path: &Path) -> rusqlite::Result<Connection> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Set up the tables, and bring old databases up to date.
+    fn init(conn: Connection) -> rusqlite::Result<Connection> {
         conn.execute("PRAGMA encoding=\"UTF-8\"", &[])?;
         conn.execute(
             "CREATE TABLE IF NOT EXISTS apicall (
@@ -33,26 +128,737 @@ impl Cache {
                       )",
             &[],
         )?;
-        Ok(Cache { conn: conn })
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ed2khash (
+                      path TEXT PRIMARY KEY,
+                      size INTEGER NOT NULL,
+                      mtime INTEGER NOT NULL,
+                      bin BLOB NOT NULL,
+                      hex TEXT NOT NULL,
+                      alt_bin BLOB,
+                      time_created INTEGER NOT NULL
+                      )",
+            &[],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sorted (
+                      path TEXT PRIMARY KEY,
+                      size INTEGER NOT NULL,
+                      mtime INTEGER NOT NULL,
+                      hex TEXT NOT NULL,
+                      time_created INTEGER NOT NULL
+                      )",
+            &[],
+        )?;
+        let version: i32 = conn.query_row("PRAGMA user_version", &[], |row| row.get(0))?;
+        if version < 2 {
+            // Sessions used to be stored without their local address. They only last half
+            // an hour anyway, so just start over.
+            conn.execute("DROP TABLE IF EXISTS session", &[])?;
+        }
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS session (
+                      user TEXT PRIMARY KEY,
+                      session TEXT NOT NULL,
+                      local_addr TEXT NOT NULL,
+                      time_created INTEGER NOT NULL
+                      )",
+            &[],
+        )?;
+        if version < 1 {
+            // Older caches stored the status line along with the data.
+            conn.execute(
+                "UPDATE apicall SET answer = CASE
+                     WHEN instr(answer, char(10)) = 0 THEN ''
+                     ELSE substr(answer, instr(answer, char(10)) + 1)
+                     END",
+                &[],
+            )?;
+            conn.execute("PRAGMA user_version = 1", &[])?;
+        }
+        if version < 2 {
+            conn.execute("PRAGMA user_version = 2", &[])?;
+        }
+        if version < 3 {
+            conn.execute("ALTER TABLE ed2khash ADD COLUMN fid INTEGER", &[])?;
+            conn.execute("PRAGMA user_version = 3", &[])?;
+        }
+        Ok(conn)
     }
 
-    pub fn get(&self, query: &str) -> Result<ServerReply> {
+    /// Move a corrupt cache database aside and start over with an empty one.
+    ///
+    /// The old database is kept next to the new one, with a `.corrupt` extension.
+    pub fn rebuild(&mut self) -> Result<()> {
+        // Close the old database before moving it.
+        self.conn = Connection::open_in_memory()?;
+        fs::rename(&self.path, self.path.with_extension("sqlite.corrupt"))?;
+        self.conn = Self::open(&self.path).map_err(|err| corrupt(&self.path, err))?;
+        Ok(())
+    }
+
+    /// Convert an sqlite error, picking out the ones that mean the database is corrupt.
+    fn check(&self, err: rusqlite::Error) -> AnidbError {
+        corrupt(&self.path, err)
+    }
+}
+
+impl CacheBackend for Cache {
+    fn get(&self, query: &str) -> Result<Option<ServerReply>> {
         let answer = self.conn.query_row(
-            "SELECT code, answer FROM apicall WHERE query = ?1",
-            &[&query],
-            |row| ServerReply {
-                code: row.get(0),
-                data: row.get(1),
+            "SELECT code, answer FROM apicall WHERE query = ?1 AND time_created > ?2",
+            &[&query, &(now() - self.max_age.as_secs() as i64)],
+            |row| {
+                let answer: String = row.get(1);
+                ServerReply {
+                    code: row.get(0),
+                    data: format!("{}\n{}", CACHED_STATUS, answer),
+                }
             },
-        )?;
-        Ok(answer)
+        );
+        match answer {
+            Ok(answer) => Ok(Some(answer)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(self.check(err)),
+        }
+    }
+
+    fn put(&self, query: &str, reply: &ServerReply) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO apicall (query, code, answer, time_created)
+                 VALUES(?, ?, ?, ?)",
+                &[&query, &reply.code, &strip_status(&reply.data), &now()],
+            )
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn invalidate(&self, query: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM apicall WHERE query = ?1", &[&query])
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM apicall", &[])
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn prune(&self, older_than: Duration) -> Result<usize> {
+        let removed = self
+            .conn
+            .execute(
+                "DELETE FROM apicall WHERE time_created <= ?1",
+                &[&(now() - older_than.as_secs() as i64)],
+            )
+            .map_err(|err| self.check(err))?;
+        Ok(removed as usize)
     }
 
-    pub fn put(&self, query: &str, reply: &ServerReply) -> Result<()> {
+    fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+
+    fn get_hash(&self, path: &Path, size: u64, mtime: i64) -> Result<Option<Ed2kHash>> {
+        let path = path.to_string_lossy().into_owned();
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT bin, alt_bin FROM ed2khash WHERE path = ?1 AND size = ?2 AND mtime = ?3",
+            )
+            .map_err(|err| self.check(err))?;
+        let mut rows = stmt
+            .query(&[&path, &(size as i64), &mtime])
+            .map_err(|err| self.check(err))?;
+        match rows.next() {
+            Some(row) => {
+                let row = row.map_err(|err| self.check(err))?;
+                let bin: Vec<u8> = row.get(0);
+                let alt_bin: Option<Vec<u8>> = row.get(1);
+                match (to_digest(&bin), alt_bin.map(|alt_bin| to_digest(&alt_bin))) {
+                    (Some(bin), None) => Ok(Some(Ed2kHash::from_parts(bin, size, None))),
+                    (Some(bin), Some(Some(alt_bin))) => {
+                        Ok(Some(Ed2kHash::from_parts(bin, size, Some(alt_bin))))
+                    }
+                    // A mangled hash is as good as none; the file just gets hashed again.
+                    _ => Ok(None),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put_hash(&self, path: &Path, mtime: i64, hash: &Ed2kHash) -> Result<()> {
         self.conn.execute(
-            "INSERT INTO apicall (query, code, answer, time_created) VALUES(?, ?, ?, ?)",
-            &[&query, &reply.code, &reply.data, &now()],
-        )?;
+            "INSERT OR REPLACE INTO ed2khash (path, size, mtime, bin, hex, alt_bin, time_created)
+             VALUES(?, ?, ?, ?, ?, ?, ?)",
+            &[
+                &path.to_string_lossy().into_owned(),
+                &(hash.size as i64),
+                &mtime,
+                &hash.bin.to_vec(),
+                &hash.hex,
+                &hash.alt_bin.map(|alt_bin| alt_bin.to_vec()),
+                &now(),
+            ],
+        ).map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn get_fid(&self, path: &Path, size: u64, mtime: i64) -> Result<Option<u32>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT fid FROM ed2khash WHERE path = ?1 AND size = ?2 AND mtime = ?3")
+            .map_err(|err| self.check(err))?;
+        let mut rows = stmt
+            .query(&[&path.to_string_lossy().into_owned(), &(size as i64), &mtime])
+            .map_err(|err| self.check(err))?;
+        match rows.next() {
+            Some(row) => {
+                let fid: Option<i64> = row.map_err(|err| self.check(err))?.get(0);
+                Ok(fid.map(|fid| fid as u32))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn put_fid(&self, path: &Path, size: u64, mtime: i64, fid: u32) -> Result<()> {
+        self.conn
+            .execute(
+                "UPDATE ed2khash SET fid = ?1 WHERE path = ?2 AND size = ?3 AND mtime = ?4",
+                &[
+                    &(fid as i64),
+                    &path.to_string_lossy().into_owned(),
+                    &(size as i64),
+                    &mtime,
+                ],
+            )
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn mark_sorted(&self, path: &Path, mtime: i64, hash: &Ed2kHash) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO sorted (path, size, mtime, hex, time_created)
+                 VALUES(?, ?, ?, ?, ?)",
+                &[
+                    &path.to_string_lossy().into_owned(),
+                    &(hash.size as i64),
+                    &mtime,
+                    &hash.hex,
+                    &now(),
+                ],
+            )
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn is_sorted(&self, path: &Path, size: u64, mtime: i64) -> Result<bool> {
+        let count: i64 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sorted WHERE path = ?1 AND size = ?2 AND mtime = ?3",
+                &[&path.to_string_lossy().into_owned(), &(size as i64), &mtime],
+                |row| row.get(0),
+            )
+            .map_err(|err| self.check(err))?;
+        Ok(count > 0)
+    }
+
+    fn get_session(
+        &self,
+        user: &str,
+        local: &SocketAddr,
+        max_age: Duration,
+    ) -> Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT session FROM session
+                 WHERE user = ?1 AND local_addr = ?2 AND time_created > ?3",
+            )
+            .map_err(|err| self.check(err))?;
+        let mut rows = stmt
+            .query(&[
+                &user,
+                &local.to_string(),
+                &(now() - max_age.as_secs() as i64),
+            ])
+            .map_err(|err| self.check(err))?;
+        match rows.next() {
+            Some(row) => Ok(Some(row.map_err(|err| self.check(err))?.get(0))),
+            None => Ok(None),
+        }
+    }
+
+    fn put_session(&self, user: &str, local: &SocketAddr, session: &str) -> Result<()> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO session (user, session, local_addr, time_created)
+                 VALUES(?, ?, ?, ?)",
+                &[&user, &session, &local.to_string(), &now()],
+            )
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+
+    fn clear_session(&self, user: &str) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM session WHERE user = ?1", &[&user])
+            .map_err(|err| self.check(err))?;
+        Ok(())
+    }
+}
+
+/// A file's size and mtime when it was hashed, its hash, and the alternate hash if it has one.
+type StoredHash = (u64, i64, [u8; 16], Option<[u8; 16]>);
+
+/// A cache that keeps everything in memory, and needs no disk. For tests and short-lived
+/// tools; everything is lost when it's dropped.
+pub struct MemoryCache {
+    replies: RefCell<HashMap<String, (i32, String, i64)>>,
+    hashes: RefCell<HashMap<PathBuf, StoredHash>>,
+    fids: RefCell<HashMap<PathBuf, (u64, i64, u32)>>,
+    sorted: RefCell<HashMap<PathBuf, (u64, i64)>>,
+    sessions: RefCell<HashMap<String, (String, SocketAddr, i64)>>,
+    /// Cached replies older than this are treated as missing.
+    pub max_age: Duration,
+}
+
+impl MemoryCache {
+    pub fn new() -> MemoryCache {
+        MemoryCache {
+            replies: RefCell::new(HashMap::new()),
+            hashes: RefCell::new(HashMap::new()),
+            fids: RefCell::new(HashMap::new()),
+            sorted: RefCell::new(HashMap::new()),
+            sessions: RefCell::new(HashMap::new()),
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+impl Default for MemoryCache {
+    fn default() -> MemoryCache {
+        MemoryCache::new()
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn get(&self, query: &str) -> Result<Option<ServerReply>> {
+        let oldest = now() - self.max_age.as_secs() as i64;
+        Ok(self.replies.borrow().get(query).and_then(|entry| {
+            let (code, ref answer, time_created) = *entry;
+            if time_created > oldest {
+                Some(ServerReply {
+                    code,
+                    data: format!("{}\n{}", CACHED_STATUS, answer),
+                })
+            } else {
+                None
+            }
+        }))
+    }
+
+    fn put(&self, query: &str, reply: &ServerReply) -> Result<()> {
+        let answer = strip_status(&reply.data).to_owned();
+        self.replies
+            .borrow_mut()
+            .insert(query.to_owned(), (reply.code, answer, now()));
         Ok(())
     }
+
+    fn invalidate(&self, query: &str) -> Result<()> {
+        self.replies.borrow_mut().remove(query);
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.replies.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn prune(&self, older_than: Duration) -> Result<usize> {
+        let oldest = now() - older_than.as_secs() as i64;
+        let mut replies = self.replies.borrow_mut();
+        let before = replies.len();
+        replies.retain(|_, &mut (_, _, time_created)| time_created > oldest);
+        Ok(before - replies.len())
+    }
+
+    fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+
+    fn get_hash(&self, path: &Path, size: u64, mtime: i64) -> Result<Option<Ed2kHash>> {
+        Ok(match self.hashes.borrow().get(path) {
+            Some(&(stored_size, stored_mtime, bin, alt_bin))
+                if stored_size == size && stored_mtime == mtime =>
+            {
+                Some(Ed2kHash::from_parts(bin, size, alt_bin))
+            }
+            _ => None,
+        })
+    }
+
+    fn put_hash(&self, path: &Path, mtime: i64, hash: &Ed2kHash) -> Result<()> {
+        self.hashes.borrow_mut().insert(
+            path.to_path_buf(),
+            (hash.size, mtime, hash.bin, hash.alt_bin),
+        );
+        self.fids.borrow_mut().remove(path);
+        Ok(())
+    }
+
+    fn get_fid(&self, path: &Path, size: u64, mtime: i64) -> Result<Option<u32>> {
+        Ok(match self.fids.borrow().get(path) {
+            Some(&(stored_size, stored_mtime, fid))
+                if stored_size == size && stored_mtime == mtime =>
+            {
+                Some(fid)
+            }
+            _ => None,
+        })
+    }
+
+    fn put_fid(&self, path: &Path, size: u64, mtime: i64, fid: u32) -> Result<()> {
+        let hashed = match self.hashes.borrow().get(path) {
+            Some(&(stored_size, stored_mtime, _, _)) => {
+                stored_size == size && stored_mtime == mtime
+            }
+            None => false,
+        };
+        if hashed {
+            self.fids
+                .borrow_mut()
+                .insert(path.to_path_buf(), (size, mtime, fid));
+        }
+        Ok(())
+    }
+
+    fn mark_sorted(&self, path: &Path, mtime: i64, hash: &Ed2kHash) -> Result<()> {
+        self.sorted
+            .borrow_mut()
+            .insert(path.to_path_buf(), (hash.size, mtime));
+        Ok(())
+    }
+
+    fn is_sorted(&self, path: &Path, size: u64, mtime: i64) -> Result<bool> {
+        Ok(self.sorted.borrow().get(path) == Some(&(size, mtime)))
+    }
+
+    fn get_session(
+        &self,
+        user: &str,
+        local: &SocketAddr,
+        max_age: Duration,
+    ) -> Result<Option<String>> {
+        let oldest = now() - max_age.as_secs() as i64;
+        Ok(match self.sessions.borrow().get(user) {
+            Some(&(ref session, addr, time_created)) if addr == *local && time_created > oldest => {
+                Some(session.clone())
+            }
+            _ => None,
+        })
+    }
+
+    fn put_session(&self, user: &str, local: &SocketAddr, session: &str) -> Result<()> {
+        self.sessions
+            .borrow_mut()
+            .insert(user.to_owned(), (session.to_owned(), *local, now()));
+        Ok(())
+    }
+
+    fn clear_session(&self, user: &str) -> Result<()> {
+        self.sessions.borrow_mut().remove(user);
+        Ok(())
+    }
+}
+
+fn corrupt(path: &Path, err: rusqlite::Error) -> AnidbError {
+    match err {
+        rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.code == ErrorCode::DatabaseCorrupt
+                || failure.code == ErrorCode::NotADatabase =>
+        {
+            AnidbError::CacheCorrupt {
+                path: path.to_path_buf(),
+            }
+        }
+        err => AnidbError::from(err),
+    }
+}
+
+/// Whether opening the cache failed because it can't be written to.
+fn unwritable(err: &AnidbError) -> bool {
+    match *err {
+        // 30 is EROFS, a read-only filesystem.
+        AnidbError::Io(ref err) => {
+            err.kind() == io::ErrorKind::PermissionDenied || err.raw_os_error() == Some(30)
+        }
+        AnidbError::SqliteError(rusqlite::Error::SqliteFailure(ref failure, _)) => {
+            failure.code == ErrorCode::ReadOnly
+                || failure.code == ErrorCode::CannotOpen
+                || failure.code == ErrorCode::PermissionDenied
+        }
+        _ => false,
+    }
+}
+
+/// The data lines of a reply, without the status line.
+fn strip_status(data: &str) -> &str {
+    match data.find('\n') {
+        Some(end) => &data[end + 1..],
+        None => "",
+    }
+}
+
+/// A stored digest, or `None` if it isn't 16 bytes long.
+fn to_digest(bytes: &[u8]) -> Option<[u8; 16]> {
+    if bytes.len() != 16 {
+        return None;
+    }
+    let mut digest = [0; 16];
+    digest.copy_from_slice(bytes);
+    Some(digest)
+}
+
+#[cfg(test)]
+mod test_unwritable {
+    use super::*;
+
+    #[test]
+    fn test_unwritable() {
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert!(unwritable(&AnidbError::Io(denied)));
+        let read_only = io::Error::from_raw_os_error(30);
+        assert!(unwritable(&AnidbError::Io(read_only)));
+        let missing = io::Error::new(io::ErrorKind::NotFound, "missing");
+        assert!(!unwritable(&AnidbError::Io(missing)));
+        assert!(!unwritable(&AnidbError::CacheCorrupt {
+            path: PathBuf::from("anidb-rs.sqlite"),
+        }));
+    }
+}
+
+/// A directory for a test's SQLite cache, emptied first so nothing from an earlier run
+/// leaks into the test.
+#[cfg(test)]
+fn fresh_cache_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+/// The same checks against both backends, since they have to behave alike.
+#[cfg(test)]
+mod test_backends {
+    use super::*;
+
+    /// Run `check` on a `MemoryCache`, and on a fresh SQLite `Cache` named after the test.
+    fn each_backend(name: &str, check: fn(&mut dyn CacheBackend)) {
+        check(&mut MemoryCache::new());
+        check(&mut Cache::new(&fresh_cache_dir(name)).unwrap());
+    }
+
+    fn pong() -> ServerReply {
+        ServerReply {
+            code: 300,
+            data: "PONG\n".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_replies() {
+        each_backend("anidb-rs-test-cache-replies", |cache| {
+            assert!(cache.get("FILE fid=1").unwrap().is_none());
+            let none = ServerReply {
+                code: 320,
+                data: "NO SUCH FILE\n".to_owned(),
+            };
+            let multiple = ServerReply {
+                code: 322,
+                data: "MULTIPLE FILES FOUND\n1879191|1879192\n".to_owned(),
+            };
+            cache.put("FILE fid=1", &none).unwrap();
+            cache.put("FILE fid=1", &multiple).unwrap();
+            let cached = cache.get("FILE fid=1").unwrap().unwrap();
+            assert_eq!(cached.code, 322);
+            // Only the data is kept, not the status text.
+            assert_eq!(cached.fields(), vec!["1879191", "1879192"]);
+
+            cache.invalidate("FILE fid=1").unwrap();
+            assert!(cache.get("FILE fid=1").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_expiry() {
+        each_backend("anidb-rs-test-cache-expiry", |cache| {
+            cache.put("PING", &pong()).unwrap();
+            assert!(cache.get("PING").unwrap().is_some());
+            // With no time to live, everything is too old.
+            cache.set_max_age(Duration::from_secs(0));
+            assert!(cache.get("PING").unwrap().is_none());
+            // A stale reply can be replaced.
+            cache.put("PING", &pong()).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_prune_and_clear() {
+        each_backend("anidb-rs-test-cache-prune", |cache| {
+            cache.put("PING", &pong()).unwrap();
+            cache.put("VERSION", &pong()).unwrap();
+            assert_eq!(cache.prune(Duration::from_secs(60)).unwrap(), 0);
+            assert_eq!(cache.prune(Duration::from_secs(0)).unwrap(), 2);
+            assert!(cache.get("PING").unwrap().is_none());
+
+            cache.put("PING", &pong()).unwrap();
+            cache.clear().unwrap();
+            assert!(cache.get("PING").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_hashes() {
+        each_backend("anidb-rs-test-cache-hashes", |cache| {
+            let path = Path::new("/anime/lwa - 01.mkv");
+            let mut alt_bin = [0; 16];
+            alt_bin[15] = 0x01;
+            let hash = Ed2kHash::from_parts([0xa5; 16], 9728000, Some(alt_bin));
+            cache.put_hash(path, 1498599583, &hash).unwrap();
+
+            let cached = cache.get_hash(path, 9728000, 1498599583).unwrap().unwrap();
+            assert_eq!(cached.bin, hash.bin);
+            assert_eq!(cached.hex, hash.hex);
+            assert_eq!(cached.size, hash.size);
+            assert_eq!(cached.alt_bin, hash.alt_bin);
+            // A changed mtime means the file changed, so the stored hash is useless.
+            assert!(cache.get_hash(path, 9728000, 1498599584).unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_sorted() {
+        each_backend("anidb-rs-test-cache-sorted", |cache| {
+            let path = Path::new("/anime/Little_Witch_Academia/lwa - 01.mkv");
+            let hash = Ed2kHash::from_parts([0; 16], 9728000, None);
+            assert!(!cache.is_sorted(path, 9728000, 1498599583).unwrap());
+            cache.mark_sorted(path, 1498599583, &hash).unwrap();
+            assert!(cache.is_sorted(path, 9728000, 1498599583).unwrap());
+            assert!(!cache.is_sorted(path, 9728000, 1498599584).unwrap());
+            let other = Path::new("/anime/other.mkv");
+            assert!(!cache.is_sorted(other, 9728000, 1498599583).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_fids() {
+        each_backend("anidb-rs-test-cache-fids", |cache| {
+            let path = Path::new("/anime/lwa.mkv");
+            let hash = Ed2kHash::from_parts([1; 16], 1000, None);
+            // Without a stored hash, there's nothing to attach the fid to.
+            cache.put_fid(path, 1000, 1498599583, 1879191).unwrap();
+            assert_eq!(cache.get_fid(path, 1000, 1498599583).unwrap(), None);
+
+            cache.put_hash(path, 1498599583, &hash).unwrap();
+            cache.put_fid(path, 1000, 1498599583, 1879191).unwrap();
+            let fid = cache.get_fid(path, 1000, 1498599583).unwrap();
+            assert_eq!(fid, Some(1879191));
+            assert_eq!(cache.get_fid(path, 1000, 1498599584).unwrap(), None);
+            assert_eq!(cache.get_fid(path, 1001, 1498599583).unwrap(), None);
+
+            // A changed file gets hashed again, and the old fid no longer applies.
+            cache.put_hash(path, 1498599584, &hash).unwrap();
+            assert_eq!(cache.get_fid(path, 1000, 1498599584).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn test_sessions() {
+        each_backend("anidb-rs-test-cache-sessions", |cache| {
+            let hour = Duration::from_secs(60 * 60);
+            let local: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+            let other_port: SocketAddr = "127.0.0.1:9002".parse().unwrap();
+            cache.put_session("foo", &local, "abcd1").unwrap();
+            let session = cache.get_session("foo", &local, hour).unwrap();
+            assert_eq!(session, Some("abcd1".to_owned()));
+            assert_eq!(cache.get_session("foo", &other_port, hour).unwrap(), None);
+            assert_eq!(cache.get_session("bar", &local, hour).unwrap(), None);
+            let expired = cache.get_session("foo", &local, Duration::from_secs(0));
+            assert_eq!(expired.unwrap(), None);
+            cache.clear_session("foo").unwrap();
+            assert_eq!(cache.get_session("foo", &local, hour).unwrap(), None);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_sqlite {
+    use super::*;
+
+    #[test]
+    fn test_short_hash_is_a_miss() {
+        let cache = Cache::new(&fresh_cache_dir("anidb-rs-test-short-hash")).unwrap();
+        let path = Path::new("/anime/short.mkv");
+        cache
+            .conn
+            .execute(
+                "INSERT OR REPLACE INTO ed2khash (path, size, mtime, bin, hex, alt_bin, time_created)
+                 VALUES(?, ?, ?, ?, ?, ?, ?)",
+                &[
+                    &path.to_string_lossy().into_owned(),
+                    &1000i64,
+                    &1i64,
+                    &vec![0u8; 4],
+                    &"00000000".to_owned(),
+                    &None::<Vec<u8>>,
+                    &now(),
+                ],
+            )
+            .unwrap();
+        assert!(cache.get_hash(path, 1000, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_corrupt_detected() {
+        let cache_dir = fresh_cache_dir("anidb-rs-test-cache-corrupt");
+        fs::create_dir_all(&cache_dir).unwrap();
+        fs::write(cache_dir.join("anidb-rs.sqlite"), vec![0x42; 4096]).unwrap();
+        match Cache::new(&cache_dir) {
+            Err(AnidbError::CacheCorrupt { path }) => {
+                assert_eq!(path, cache_dir.join("anidb-rs.sqlite"))
+            }
+            Err(err) => panic!("Expected CacheCorrupt, got {:?}", err),
+            Ok(_) => panic!("Expected CacheCorrupt, got a cache"),
+        }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let cache_dir = fresh_cache_dir("anidb-rs-test-cache-rebuild");
+        let mut cache = Cache::new(&cache_dir).unwrap();
+        let reply = ServerReply {
+            code: 300,
+            data: "PONG\n".to_owned(),
+        };
+        cache.put("PING", &reply).unwrap();
+        cache.rebuild().unwrap();
+        assert!(cache.get("PING").unwrap().is_none());
+        assert!(cache_dir.join("anidb-rs.sqlite.corrupt").exists());
+    }
 }
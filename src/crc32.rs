@@ -0,0 +1,78 @@
+//! CRC-32 (the IEEE polynomial, as used by zip and in release file names), which AniDB also
+//! records for every file.
+
+/// The reversed IEEE polynomial.
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// Incremental CRC-32. Feed it with `update`, then read the checksum with `finalize`.
+#[derive(Clone)]
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLYNOMIAL
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        Crc32 { table, crc: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            let index = ((self.crc ^ u32::from(byte)) & 0xff) as usize;
+            self.crc = (self.crc >> 8) ^ self.table[index];
+        }
+    }
+
+    /// The checksum of everything passed to `update` so far.
+    pub fn finalize(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+/// The CRC-32 of `data`, in one call.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finalize()
+}
+
+#[cfg(test)]
+mod test_crc32 {
+    use super::*;
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut crc = Crc32::new();
+        crc.update(b"12345");
+        crc.update(b"6789");
+        assert_eq!(crc.finalize(), crc32(b"123456789"));
+    }
+}
@@ -1,57 +1,243 @@
+use cache::CacheBackend;
+use crc32::Crc32;
 use crypto::digest::Digest;
-use errors::Result;
-use md4::Md4;
+use errors::{AnidbError, Result};
+use md4::{md4_digest, Md4};
+use rayon::prelude::*;
+use rayon::{current_num_threads, ThreadPoolBuilder};
 use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Condvar, Mutex};
+use std::time::UNIX_EPOCH;
 
 const BLOCKSIZE: usize = 9500 * 1024;
 
+/// How much `from_file` reads at a time.
+const READ_SIZE: usize = 64 * 1024;
+
 #[derive(Debug)]
 pub struct Ed2kHash {
     pub bin: [u8; 16],
     pub size: u64,
     pub hex: String,
+    /// For files whose size is an exact multiple of the block size, there are two competing
+    /// ways to compute the hash: with or without an extra empty block at the end. `bin` is
+    /// without; this is the variant with.
+    pub alt_bin: Option<[u8; 16]>,
+    /// The CRC-32 of the file, if it was asked for with `from_file_with_crc32`.
+    pub crc32: Option<u32>,
 }
 
 impl Ed2kHash {
     pub fn from_file(filename: &Path) -> Result<Ed2kHash> {
-        let mut md4_digest = [0; 16];
+        let mut file = File::open(filename)?;
+        let file_size = file.metadata()?.len();
+        Self::from_reader(&mut file, file_size, None, &mut |_, _| {})
+    }
 
+    /// Like `from_file`, but calls `progress` with the bytes hashed so far and the file size
+    /// after each block, and once more at the end. Hashing a big file takes a while, so this
+    /// is for showing how far along it is.
+    pub fn from_file_with_progress<F: FnMut(u64, u64)>(
+        filename: &Path,
+        mut progress: F,
+    ) -> Result<Ed2kHash> {
         let mut file = File::open(filename)?;
-        let file_info = file.metadata()?;
-        let file_size = file_info.len() as usize;
+        let file_size = file.metadata()?.len();
+        Self::from_reader(&mut file, file_size, None, &mut progress)
+    }
 
-        let mut temp_buffer = vec![0; BLOCKSIZE].into_boxed_slice();
-        let mut ctx_f = Md4::new();
+    /// Like `from_file`, but also computes the CRC-32 in the same pass over the file, for
+    /// checking against the one AniDB has or the one in the file name.
+    pub fn from_file_with_crc32(filename: &Path) -> Result<Ed2kHash> {
+        let mut file = File::open(filename)?;
+        let file_size = file.metadata()?.len();
+        Self::from_reader(&mut file, file_size, Some(Crc32::new()), &mut |_, _| {})
+    }
 
-        let mut blocks = file_size / BLOCKSIZE;
-        if file_size % BLOCKSIZE > 0 {
-            blocks += 1;
+    /// Hash up to `file_size` bytes from the reader, and feed them to `crc32` as well if
+    /// given. See `from_file_with_progress` for `progress`.
+    fn from_reader<R: Read>(
+        reader: &mut R,
+        file_size: u64,
+        mut crc32: Option<Crc32>,
+        progress: &mut dyn FnMut(u64, u64),
+    ) -> Result<Ed2kHash> {
+        let mut reader = reader.take(file_size);
+        let mut hasher = Ed2kHasher::new();
+        let mut buffer = vec![0; READ_SIZE];
+        let mut hashed = 0;
+        let mut reported = 0;
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => {
+                    if hashed != reported {
+                        progress(hashed, file_size);
+                    }
+                    let mut hash = hasher.finalize();
+                    hash.crc32 = crc32.map(|crc32| crc32.finalize());
+                    return Ok(hash);
+                }
+                Ok(n) => {
+                    hasher.update(&buffer[..n]);
+                    if let Some(ref mut crc32) = crc32 {
+                        crc32.update(&buffer[..n]);
+                    }
+                    hashed += n as u64;
+                    if hashed / BLOCKSIZE as u64 > reported / BLOCKSIZE as u64 {
+                        progress(hashed, file_size);
+                        reported = hashed;
+                    }
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
         }
+    }
 
-        for _ in 0..blocks {
-            let mut ctx_i = Md4::new();
+    /// Like `from_file`, but hashes the blocks of the file on all of rayon's threads at once.
+    /// Worth it for big files on a multi-core machine. Reads one block per thread into memory
+    /// at a time, so uses more memory than `from_file`.
+    pub fn from_file_parallel(filename: &Path) -> Result<Ed2kHash> {
+        let mut file = File::open(filename)?;
+        let file_size = file.metadata()?.len();
+        let batch_size = current_num_threads().max(1) as u64;
+        let mut block_digests = Vec::new();
 
-            let read_size = file.read(&mut temp_buffer)?;
+        let mut remaining = block_count(file_size);
+        while remaining > 0 {
+            let batch = remaining.min(batch_size);
+            let mut blocks = Vec::with_capacity(batch as usize);
+            for _ in 0..batch {
+                let mut block = vec![0; BLOCKSIZE];
+                let read_size = read_block(&mut file, &mut block)?;
+                block.truncate(read_size);
+                blocks.push(block);
+            }
+            let digests: Vec<[u8; 16]> = blocks.par_iter().map(|block| md4_digest(block)).collect();
+            for digest in &digests {
+                block_digests.extend_from_slice(digest);
+            }
+            remaining -= batch;
+        }
 
-            ctx_i.input(&temp_buffer[..read_size]);
-            ctx_i.result(&mut md4_digest);
+        Ok(Self::from_block_digests(&block_digests, file_size))
+    }
 
-            ctx_f.input(&md4_digest);
+    /// Chain the concatenated MD4 digests of every block into the final hash.
+    fn from_block_digests(block_digests: &[u8], file_size: u64) -> Ed2kHash {
+        let mut digest = [0; 16];
+        if block_digests.len() > 16 {
+            digest = md4_digest(block_digests);
+        } else if block_digests.len() == 16 {
+            digest.copy_from_slice(block_digests);
         }
 
-        if blocks > 1 {
-            ctx_f.result(&mut md4_digest);
+        let alt_bin = if has_alternate(file_size) {
+            let mut ctx_f = Md4::new();
+            ctx_f.input(block_digests);
+            ctx_f.input(&md4_digest(&[]));
+            let mut alt_digest = [0; 16];
+            ctx_f.result(&mut alt_digest);
+            Some(alt_digest)
+        } else {
+            None
+        };
+
+        Ed2kHash {
+            bin: digest,
+            hex: Self::hex(digest),
+            size: file_size,
+            alt_bin,
+            crc32: None,
         }
+    }
+
+    /// Like `from_file`, but reuses a hash stored in the cache if the file's size and
+    /// modification time haven't changed. Freshly computed hashes are stored.
+    pub fn from_file_cached(filename: &Path, cache: &dyn CacheBackend) -> Result<Ed2kHash> {
+        match Self::from_cache(filename, cache)? {
+            Some(hash) => Ok(hash),
+            None => {
+                let hash = Self::from_file(filename)?;
+                cache.put_hash(filename, Self::mtime(filename)?, &hash)?;
+                Ok(hash)
+            }
+        }
+    }
 
-        Ok(Ed2kHash {
-            bin: md4_digest,
-            hex: Self::hex(md4_digest),
-            size: file_size as u64,
+    /// Look up a previously computed hash in the cache, without reading the file.
+    ///
+    /// Returns `None` if there's no hash stored, or the file has changed since.
+    pub fn from_cache(filename: &Path, cache: &dyn CacheBackend) -> Result<Option<Ed2kHash>> {
+        let size = filename.metadata()?.len();
+        cache.get_hash(filename, size, Self::mtime(filename)?)
+    }
+
+    /// The variant of this hash with an extra empty block, if the file size makes that
+    /// ambiguous. See `alt_bin`.
+    pub fn alternate(&self) -> Option<Ed2kHash> {
+        self.alt_bin.map(|alt_bin| Ed2kHash {
+            bin: alt_bin,
+            hex: Self::hex(alt_bin),
+            size: self.size,
+            alt_bin: Some(self.bin),
+            crc32: self.crc32,
         })
     }
 
+    /// A link of the form `ed2k://|file|name|size|hash|/`. Any `|` in the name is escaped,
+    /// since it would end the field.
+    pub fn to_ed2k_link(&self, filename: &str) -> String {
+        format!(
+            "ed2k://|file|{}|{}|{}|/",
+            filename.replace('|', "%7C"),
+            self.size,
+            self.hex
+        )
+    }
+
+    /// Parse the size and hash out of an `ed2k://|file|...` link, as made by `to_ed2k_link`.
+    ///
+    /// The link doesn't say whether the hash is the one with or without the extra empty
+    /// block, so `alt_bin` is always `None`.
+    pub fn from_ed2k_link(link: &str) -> Result<Ed2kHash> {
+        let invalid = || AnidbError::ParseField {
+            field: "ed2k link",
+            value: link.to_owned(),
+        };
+        if !link.starts_with("ed2k://|file|") {
+            return Err(invalid());
+        }
+        let fields: Vec<&str> = link["ed2k://|file|".len()..].split('|').collect();
+        if fields.len() < 3 {
+            return Err(invalid());
+        }
+        let size = fields[1].parse().map_err(|_| invalid())?;
+        Ok(Self::from_parts(parse_hex(fields[2])?, size, None))
+    }
+
+    /// Rebuild a hash from its stored parts.
+    pub(crate) fn from_parts(bin: [u8; 16], size: u64, alt_bin: Option<[u8; 16]>) -> Ed2kHash {
+        Ed2kHash {
+            bin,
+            hex: Self::hex(bin),
+            size,
+            alt_bin,
+            crc32: None,
+        }
+    }
+
+    fn mtime(filename: &Path) -> Result<i64> {
+        let modified = filename.metadata()?.modified()?;
+        Ok(modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0))
+    }
+
     fn hex(bin: [u8; 16]) -> String {
         let mut ret = String::with_capacity(32);
         for hex in bin.iter() {
@@ -60,3 +246,517 @@ impl Ed2kHash {
         ret
     }
 }
+
+/// Read until the buffer is full or the reader runs out, returning how much was read.
+fn read_block<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Computes an ed2k hash from data fed in pieces, e.g. as it arrives over the network. The
+/// pieces can be any size; they're split into blocks here.
+pub struct Ed2kHasher {
+    block: Md4,
+    /// Bytes hashed into `block` so far.
+    block_len: usize,
+    block_digests: Vec<u8>,
+    size: u64,
+}
+
+impl Ed2kHasher {
+    pub fn new() -> Ed2kHasher {
+        Ed2kHasher {
+            block: Md4::new(),
+            block_len: 0,
+            block_digests: Vec::new(),
+            size: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.size += data.len() as u64;
+        while !data.is_empty() {
+            let take = data.len().min(BLOCKSIZE - self.block_len);
+            self.block.input(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+            if self.block_len == BLOCKSIZE {
+                self.finish_block();
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> Ed2kHash {
+        if self.block_len > 0 {
+            self.finish_block();
+        }
+        Ed2kHash::from_block_digests(&self.block_digests, self.size)
+    }
+
+    fn finish_block(&mut self) {
+        let mut digest = [0; 16];
+        self.block.result(&mut digest);
+        self.block.reset();
+        self.block_len = 0;
+        self.block_digests.extend_from_slice(&digest);
+    }
+}
+
+impl Default for Ed2kHasher {
+    fn default() -> Ed2kHasher {
+        Ed2kHasher::new()
+    }
+}
+
+/// Parse a hash written as 32 hex digits, in either case, as in `Ed2kHash::hex`.
+pub fn parse_hex(hex: &str) -> Result<[u8; 16]> {
+    if hex.len() != 32 {
+        return Err(AnidbError::Error(format!(
+            "Invalid ed2k hash {:?}: expected 32 hex digits, got {} characters",
+            hex,
+            hex.chars().count()
+        )));
+    }
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(AnidbError::Error(format!(
+            "Invalid ed2k hash {:?}: it has characters that aren't hex digits",
+            hex
+        )));
+    }
+    let mut bin = [0; 16];
+    for (i, byte) in bin.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).expect("hex digits");
+    }
+    Ok(bin)
+}
+
+/// Number of blocks in a file of the given size. Sizes are kept as `u64`, so files over 4GB
+/// work on 32-bit targets too.
+fn block_count(size: u64) -> u64 {
+    (size + BLOCKSIZE as u64 - 1) / BLOCKSIZE as u64
+}
+
+/// Whether a file of the given size has an alternate hash. See `Ed2kHash::alt_bin`.
+fn has_alternate(size: u64) -> bool {
+    size > 0 && size % BLOCKSIZE as u64 == 0
+}
+
+/// Options for hashing many files at once, with `hash_files`.
+#[derive(Debug, Clone)]
+pub struct HashOptions {
+    /// Number of worker threads. Zero means one per CPU.
+    pub threads: usize,
+    /// Maximum number of files being hashed at the same time, regardless of thread count.
+    /// Keeps the disk from seeking between too many files at once, which slows down
+    /// spinning disks and network shares more than it speeds up hashing.
+    pub max_in_flight: usize,
+}
+
+impl Default for HashOptions {
+    fn default() -> HashOptions {
+        HashOptions {
+            threads: 0,
+            max_in_flight: 4,
+        }
+    }
+}
+
+/// Hash many files in parallel.
+///
+/// If a cache is given, hashes stored there are reused and new ones are stored. A hash that
+/// can't be stored is still returned, with a warning logged. Results come back in the same
+/// order as `paths`.
+pub fn hash_files(
+    paths: Vec<PathBuf>,
+    options: &HashOptions,
+    cache: Option<&dyn CacheBackend>,
+) -> Vec<(PathBuf, Result<Ed2kHash>)> {
+    let mut results: Vec<Option<Result<Ed2kHash>>> = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let cached = match cache {
+            Some(cache) => Ed2kHash::from_cache(path, cache).unwrap_or(None),
+            None => None,
+        };
+        results.push(cached.map(Ok));
+    }
+    let uncached: Vec<usize> = (0..paths.len()).filter(|&i| results[i].is_none()).collect();
+
+    let (sender, receiver) = mpsc::channel();
+    let uncached_paths = uncached.iter().map(|&i| paths[i].clone()).collect();
+    hash_files_to(uncached_paths, options, sender);
+    for (i, path, hash) in receiver {
+        if let (Some(cache), Ok(hashed)) = (cache, &hash) {
+            let stored =
+                Ed2kHash::mtime(&path).and_then(|mtime| cache.put_hash(&path, mtime, hashed));
+            if let Err(err) = stored {
+                warn!("Storing the hash of {:?}: {}", path, err);
+            }
+        }
+        results[uncached[i]] = Some(hash);
+    }
+
+    paths
+        .into_iter()
+        .zip(results)
+        .map(|(path, result)| (path, result.expect("Every file is hashed")))
+        .collect()
+}
+
+/// Hash many files in parallel like `hash_files`, but send each hash on `results` as soon
+/// as it's ready, along with the file's index in `paths`. Returns once every file is
+/// hashed, or early if the receiving end is dropped. If the hashing threads can't be
+/// started, every file gets that error instead.
+///
+/// There's no cache here, so the caller can look files up beforehand and store the hashes
+/// as they come in, without lending out the cache for as long as the hashing takes.
+pub fn hash_files_to(
+    paths: Vec<PathBuf>,
+    options: &HashOptions,
+    results: Sender<(usize, PathBuf, Result<Ed2kHash>)>,
+) {
+    let pool = match ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .build()
+    {
+        Ok(pool) => pool,
+        Err(err) => {
+            for (i, path) in paths.into_iter().enumerate() {
+                let err = io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Failed to start hashing threads: {}", err),
+                );
+                if results.send((i, path, Err(err.into()))).is_err() {
+                    break;
+                }
+            }
+            return;
+        }
+    };
+    let in_flight = Semaphore::new(options.max_in_flight.max(1));
+    pool.install(|| {
+        // A failed send means no one is listening any more, so stop there.
+        let _ =
+            paths
+                .into_par_iter()
+                .enumerate()
+                .try_for_each_with(results, |results, (i, path)| {
+                    in_flight.acquire();
+                    let hash = Ed2kHash::from_file(&path);
+                    in_flight.release();
+                    results.send((i, path, hash))
+                });
+    });
+}
+
+/// A counting semaphore, for limiting how many files are hashed at once.
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(count: usize) -> Semaphore {
+        Semaphore {
+            available: Mutex::new(count),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) {
+        let mut available = self.available.lock().expect("lock");
+        while *available == 0 {
+            available = self.released.wait(available).expect("lock");
+        }
+        *available -= 1;
+    }
+
+    fn release(&self) {
+        *self.available.lock().expect("lock") += 1;
+        self.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test_block_count {
+    use super::*;
+
+    #[test]
+    fn test_block_count_large_file() {
+        let block = BLOCKSIZE as u64;
+        assert_eq!(block_count(0), 0);
+        assert_eq!(block_count(1), 1);
+        assert_eq!(block_count(block), 1);
+        assert_eq!(block_count(block + 1), 2);
+        // Over 4GB, which would wrap around in a 32-bit usize.
+        let size = 5 * 1024 * 1024 * 1024;
+        assert_eq!(block_count(size), 552);
+        assert!(!has_alternate(size));
+        assert!(has_alternate(1000 * block));
+    }
+}
+
+#[cfg(test)]
+mod test_from_file {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// A bit over one block, so the hash covers two blocks.
+    fn two_block_data() -> Vec<u8> {
+        (0..BLOCKSIZE + 1000).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Hands out at most 1000 bytes per read, like a pipe might.
+    struct ShortReads<'a>(&'a [u8]);
+
+    impl<'a> Read for ShortReads<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_from_file_reference_hash() {
+        let path = env::temp_dir().join("anidb-rs-test-reference-hash.mkv");
+        fs::write(&path, two_block_data()).unwrap();
+        let hash = Ed2kHash::from_file(&path).unwrap();
+        assert_eq!(hash.size, BLOCKSIZE as u64 + 1000);
+        assert_eq!(hash.hex, "c31099fb749acdd8eddc5bb0f7473689");
+
+        fs::write(&path, b"abc").unwrap();
+        let hash = Ed2kHash::from_file(&path).unwrap();
+        assert_eq!(hash.hex, "a448017aaf21d8525fc10ae87aa6729d");
+    }
+
+    #[test]
+    fn test_from_file_with_crc32() {
+        let path = env::temp_dir().join("anidb-rs-test-crc32-hash.mkv");
+        let data = two_block_data();
+        fs::write(&path, &data).unwrap();
+        let hash = Ed2kHash::from_file_with_crc32(&path).unwrap();
+        assert_eq!(hash.hex, "c31099fb749acdd8eddc5bb0f7473689");
+        assert_eq!(hash.crc32, Some(::crc32::crc32(&data)));
+        assert_eq!(Ed2kHash::from_file(&path).unwrap().crc32, None);
+
+        fs::write(&path, b"123456789").unwrap();
+        let hash = Ed2kHash::from_file_with_crc32(&path).unwrap();
+        assert_eq!(hash.crc32, Some(0xcbf4_3926));
+    }
+
+    #[test]
+    fn test_from_file_with_progress() {
+        let path = env::temp_dir().join("anidb-rs-test-progress-hash.mkv");
+        let total = 2 * BLOCKSIZE as u64 + 100_000;
+        fs::write(&path, vec![7; total as usize]).unwrap();
+        let mut calls = Vec::new();
+        let hash =
+            Ed2kHash::from_file_with_progress(&path, |done, total| calls.push((done, total)))
+                .unwrap();
+        assert_eq!(hash.hex, Ed2kHash::from_file(&path).unwrap().hex);
+        // One call after each of the two full blocks, and one at the end.
+        assert_eq!(calls.len(), 3);
+        assert!(calls[0].0 >= BLOCKSIZE as u64 && calls[0].0 < calls[1].0);
+        assert_eq!(calls[2], (total, total));
+    }
+
+    #[test]
+    fn test_from_file_parallel() {
+        let path = env::temp_dir().join("anidb-rs-test-parallel-hash.mkv");
+        fs::write(&path, two_block_data()).unwrap();
+        let hash = Ed2kHash::from_file_parallel(&path).unwrap();
+        assert_eq!(hash.hex, "c31099fb749acdd8eddc5bb0f7473689");
+        assert_eq!(hash.size, BLOCKSIZE as u64 + 1000);
+
+        let exact = vec![7; BLOCKSIZE];
+        fs::write(&path, &exact).unwrap();
+        let parallel = Ed2kHash::from_file_parallel(&path).unwrap();
+        let serial = Ed2kHash::from_file(&path).unwrap();
+        assert_eq!(parallel.hex, serial.hex);
+        assert_eq!(parallel.alt_bin, serial.alt_bin);
+        assert!(parallel.alt_bin.is_some());
+    }
+
+    #[test]
+    fn test_from_reader_short_reads() {
+        let data = two_block_data();
+        let hash = Ed2kHash::from_reader(
+            &mut ShortReads(&data),
+            data.len() as u64,
+            None,
+            &mut |_, _| {},
+        )
+        .unwrap();
+        assert_eq!(hash.hex, "c31099fb749acdd8eddc5bb0f7473689");
+    }
+}
+
+#[cfg(test)]
+mod test_hasher {
+    use super::*;
+
+    #[test]
+    fn test_hasher_chunked_matches_whole() {
+        let data: Vec<u8> = (0..2 * BLOCKSIZE + 5000).map(|i| (i % 253) as u8).collect();
+
+        let mut whole = Ed2kHasher::new();
+        whole.update(&data);
+        let whole = whole.finalize();
+
+        let mut chunked = Ed2kHasher::new();
+        for chunk in data.chunks(12345) {
+            chunked.update(chunk);
+        }
+        let chunked = chunked.finalize();
+
+        assert_eq!(whole.hex, chunked.hex);
+        assert_eq!(whole.size, data.len() as u64);
+        assert_eq!(chunked.size, data.len() as u64);
+    }
+
+    #[test]
+    fn test_hasher_block_boundary() {
+        let mut hasher = Ed2kHasher::new();
+        hasher.update(&vec![7; BLOCKSIZE]);
+        let hash = hasher.finalize();
+        assert_eq!(hash.hex, Ed2kHash::hex(md4_digest(&vec![7; BLOCKSIZE])));
+        assert!(hash.alt_bin.is_some());
+    }
+}
+
+#[cfg(test)]
+mod test_ed2k_link {
+    use super::*;
+
+    #[test]
+    fn test_ed2k_link_round_trip() {
+        let hash = Ed2kHash::from_parts([0xab; 16], 123456, None);
+        let link = hash.to_ed2k_link("[Group] Show - 01.mkv");
+        assert_eq!(
+            link,
+            "ed2k://|file|[Group] Show - 01.mkv|123456|abababababababababababababababab|/"
+        );
+        let parsed = Ed2kHash::from_ed2k_link(&link).unwrap();
+        assert_eq!(parsed.bin, hash.bin);
+        assert_eq!(parsed.size, hash.size);
+        assert_eq!(parsed.hex, hash.hex);
+    }
+
+    #[test]
+    fn test_ed2k_link_uppercase_and_pipe() {
+        let hash = Ed2kHash::from_parts([0xcd; 16], 1, None);
+        assert_eq!(
+            hash.to_ed2k_link("a|b"),
+            "ed2k://|file|a%7Cb|1|cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd|/"
+        );
+        let parsed =
+            Ed2kHash::from_ed2k_link("ed2k://|file|x|1|CDCDCDCDCDCDCDCDCDCDCDCDCDCDCDCD|/")
+                .unwrap();
+        assert_eq!(parsed.hex, hash.hex);
+    }
+
+    #[test]
+    fn test_ed2k_link_invalid() {
+        for link in &[
+            "http://example.com",
+            "ed2k://|file|x|notasize|abababababababababababababababab|/",
+            "ed2k://|file|x|1|abab|/",
+            "ed2k://|file|x|1|zzababababababababababababababab|/",
+            "ed2k://|file|x|1",
+        ] {
+            assert!(Ed2kHash::from_ed2k_link(link).is_err(), "{}", link);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_parse_hex {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        let bin = parse_hex("A448017AAF21D8525FC10AE87AA6729D").unwrap();
+        assert_eq!(Ed2kHash::hex(bin), "a448017aaf21d8525fc10ae87aa6729d");
+    }
+
+    #[test]
+    fn test_parse_hex_invalid() {
+        for hex in &[
+            "",
+            "a448017aaf21d8525fc10ae87aa6729",
+            "a448017aaf21d8525fc10ae87aa6729d0",
+            "g448017aaf21d8525fc10ae87aa6729d",
+            "a448017aaf21d8525fc10ae87aa672 d",
+            // 32 bytes, but not 32 characters.
+            "a448017aaf21d8525fc10ae87aa672é",
+        ] {
+            assert!(parse_hex(hex).is_err(), "{:?}", hex);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_hash_files {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_hash_files_matches_from_file() {
+        let dir = env::temp_dir().join("anidb-rs-test-hash-files");
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<PathBuf> = (0..5)
+            .map(|i| {
+                let path = dir.join(format!("{}.mkv", i));
+                fs::write(&path, vec![i as u8; 1000 * (i + 1)]).unwrap();
+                path
+            })
+            .collect();
+        let options = HashOptions {
+            threads: 4,
+            max_in_flight: 2,
+        };
+
+        let hashed = hash_files(paths.clone(), &options, None);
+        assert_eq!(hashed.len(), paths.len());
+        for ((path, hash), expected) in hashed.iter().zip(&paths) {
+            assert_eq!(path, expected);
+            let expected_hash = Ed2kHash::from_file(expected).unwrap();
+            assert_eq!(hash.as_ref().unwrap().hex, expected_hash.hex);
+        }
+    }
+
+    #[test]
+    fn test_hash_files_to_sends_every_file() {
+        let dir = env::temp_dir().join("anidb-rs-test-hash-files-to");
+        fs::create_dir_all(&dir).unwrap();
+        let paths: Vec<PathBuf> = (0..5)
+            .map(|i| {
+                let path = dir.join(format!("{}.mkv", i));
+                fs::write(&path, vec![i as u8; 1000 * (i + 1)]).unwrap();
+                path
+            })
+            .collect();
+
+        let (sender, receiver) = mpsc::channel();
+        hash_files_to(paths.clone(), &HashOptions::default(), sender);
+        let mut sent: Vec<_> = receiver.into_iter().collect();
+        sent.sort_by_key(|&(i, _, _)| i);
+        assert_eq!(sent.len(), paths.len());
+        for (i, path, hash) in sent {
+            assert_eq!(path, paths[i]);
+            let expected_hash = Ed2kHash::from_file(&paths[i]).unwrap();
+            assert_eq!(hash.unwrap().hex, expected_hash.hex);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! AES-128-ECB session encryption, as set up by the ENCRYPT command.
+
+use crypto::aes::{self, KeySize};
+use crypto::blockmodes::PkcsPadding;
+use crypto::buffer::{BufferResult, ReadBuffer, RefReadBuffer, RefWriteBuffer, WriteBuffer};
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use errors::{AnidbError, Result};
+
+pub type Key = [u8; 16];
+
+/// The session key is the MD5 of the API key followed by the salt AniDB sent.
+pub fn derive_key(api_key: &str, salt: &str) -> Key {
+    let mut md5 = Md5::new();
+    md5.input_str(api_key);
+    md5.input_str(salt);
+    let mut key = [0; 16];
+    md5.result(&mut key);
+    key
+}
+
+pub fn encrypt(key: &Key, data: &[u8]) -> Vec<u8> {
+    let mut encryptor = aes::ecb_encryptor(KeySize::KeySize128, key, PkcsPadding);
+    let mut output = Vec::with_capacity(data.len() + 16);
+    let mut input = RefReadBuffer::new(data);
+    let mut buffer = [0; 4096];
+    loop {
+        let mut out = RefWriteBuffer::new(&mut buffer);
+        // Encryption with padding can't fail; only decryption can see a bad block.
+        let result = encryptor
+            .encrypt(&mut input, &mut out, true)
+            .expect("AES encryption failed");
+        output.extend_from_slice(out.take_read_buffer().take_remaining());
+        if let BufferResult::BufferUnderflow = result {
+            return output;
+        }
+    }
+}
+
+pub fn decrypt(key: &Key, data: &[u8]) -> Result<Vec<u8>> {
+    let mut decryptor = aes::ecb_decryptor(KeySize::KeySize128, key, PkcsPadding);
+    let mut output = Vec::with_capacity(data.len());
+    let mut input = RefReadBuffer::new(data);
+    let mut buffer = [0; 4096];
+    loop {
+        let mut out = RefWriteBuffer::new(&mut buffer);
+        let result = decryptor
+            .decrypt(&mut input, &mut out, true)
+            .map_err(|_| AnidbError::StaticError("Couldn't decrypt the reply"))?;
+        output.extend_from_slice(out.take_read_buffer().take_remaining());
+        if let BufferResult::BufferUnderflow = result {
+            return Ok(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_encryption {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let key = derive_key("apikey", "salt");
+        let message = b"FILE size=1&ed2k=00112233445566778899aabbccddeeff";
+        let encrypted = encrypt(&key, message);
+        assert_eq!(encrypted.len() % 16, 0);
+        assert_ne!(&encrypted[..message.len()], &message[..]);
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), &message[..]);
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let encrypted = encrypt(&derive_key("apikey", "salt"), b"220 FILE");
+        let decrypted = decrypt(&derive_key("apikey", "pepper"), &encrypted);
+        assert!(decrypted.map(|d| d != b"220 FILE").unwrap_or(true));
+    }
+
+    #[test]
+    fn test_derive_key() {
+        // MD5("") is the well known d41d8cd98f00b204e9800998ecf8427e.
+        let key = derive_key("", "");
+        assert_eq!(&key[..4], &[0xd4, 0x1d, 0x8c, 0xd9]);
+    }
+}
@@ -4,6 +4,7 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 use std::num;
+use std::path::PathBuf;
 use std::result;
 use std::str;
 
@@ -18,7 +19,45 @@ pub enum AnidbError {
     ErrorCode(i32, String),
     Error(String),
     SqliteError(rusqlite::Error),
+    /// AniDB sent a zero-length datagram. Usually a NAT or keepalive artifact, rather than a
+    /// real reply, so it's worth trying again.
+    EmptyReply,
+    /// No reply came within the socket's read timeout; see `Anidb::set_timeout`.
+    Timeout,
+    /// The reply is too short to hold a reply code.
+    ShortReply(Vec<u8>),
+    /// The cache database is damaged; see `Cache::rebuild`.
+    CacheCorrupt {
+        path: PathBuf,
+    },
     NoSuchFile,
+    /// A field of a reply is missing, if `value` is empty, or couldn't be parsed.
+    ParseField {
+        field: &'static str,
+        value: String,
+    },
+    /// The query matched several files; these are their fids.
+    MultipleFiles(Vec<u32>),
+    /// The query matched several mylist entries. AniDB only summarizes them, in this reply
+    /// text, so look them up one by one instead.
+    MultipleMylistEntries(String),
+    /// There's already a vote for this, with this value, times 100.
+    AlreadyVoted(u16),
+    /// AniDB refused the login because the client version is too old; see
+    /// `Anidb::client_version`.
+    ClientOutdated,
+    /// AniDB refused the login because it has banned the client name, with this reason. The
+    /// client needs fixing or registering again; see `Anidb::client_name`.
+    ClientBanned(String),
+    /// AniDB has banned this client, with this reason. Nothing is sent until the ban runs
+    /// out; see `Anidb::banned_until`.
+    Banned(String),
+    /// A reply carried a different tag than the command it should answer, even after
+    /// throwing a few stray replies away.
+    TagMismatch {
+        expected: String,
+        reply: String,
+    },
 }
 
 impl fmt::Display for AnidbError {
@@ -31,7 +70,40 @@ impl fmt::Display for AnidbError {
             AnidbError::ErrorCode(size, ref string) => write!(f, "Error {} - {}", size, string),
             AnidbError::Error(ref string) => write!(f, "{}", string),
             AnidbError::SqliteError(ref err) => err.fmt(f),
+            AnidbError::EmptyReply => write!(f, "Empty reply"),
+            AnidbError::Timeout => write!(f, "Timed out waiting for a reply"),
+            AnidbError::ShortReply(ref reply) => {
+                write!(f, "Reply too short: {:?}", String::from_utf8_lossy(reply))
+            }
+            AnidbError::CacheCorrupt { ref path } => write!(f, "Cache {:?} is corrupt", path),
             AnidbError::NoSuchFile => write!(f, "No such file"),
+            AnidbError::ParseField { field, ref value } if value.is_empty() => {
+                write!(f, "Reply has no {}", field)
+            }
+            AnidbError::ParseField { field, ref value } => {
+                write!(f, "Reply has invalid {}: {:?}", field, value)
+            }
+            AnidbError::MultipleFiles(ref fids) => write!(f, "Found multiple files: {:?}", fids),
+            AnidbError::MultipleMylistEntries(ref summary) => {
+                write!(f, "Found multiple mylist entries: {}", summary)
+            }
+            AnidbError::AlreadyVoted(value) => {
+                write!(f, "Already voted {}.{:02}", value / 100, value % 100)
+            }
+            AnidbError::ClientOutdated => write!(
+                f,
+                "AniDB says this client version is outdated; update the client"
+            ),
+            AnidbError::ClientBanned(ref reason) => write!(
+                f,
+                "AniDB has banned this client ({}); it needs updating or registering again",
+                reason
+            ),
+            AnidbError::Banned(ref reason) => write!(f, "Banned by AniDB: {}", reason),
+            AnidbError::TagMismatch {
+                ref expected,
+                ref reply,
+            } => write!(f, "Expected a reply tagged {}, got {:?}", expected, reply),
         }
     }
 }
@@ -46,7 +118,19 @@ impl Error for AnidbError {
             AnidbError::ErrorCode(_size, ref _string) => "Error Code",
             AnidbError::Error(ref string) => string.as_str(),
             AnidbError::SqliteError(ref err) => err.description(),
+            AnidbError::EmptyReply => "Empty reply",
+            AnidbError::Timeout => "Timed out",
+            AnidbError::ShortReply(_) => "Reply too short",
+            AnidbError::CacheCorrupt { .. } => "Cache is corrupt",
             AnidbError::NoSuchFile => "No such file",
+            AnidbError::ParseField { .. } => "Invalid reply field",
+            AnidbError::MultipleFiles(_) => "Found multiple files",
+            AnidbError::MultipleMylistEntries(_) => "Found multiple mylist entries",
+            AnidbError::AlreadyVoted(_) => "Already voted",
+            AnidbError::ClientOutdated => "Client version outdated",
+            AnidbError::ClientBanned(_) => "Client banned",
+            AnidbError::Banned(_) => "Banned by AniDB",
+            AnidbError::TagMismatch { .. } => "Reply has the wrong tag",
         }
     }
 }
@@ -0,0 +1,69 @@
+//! Undoing AniDB's escaping of reply fields.
+
+/// Decode `%HH` escapes. Anything that isn't a valid escape is left as it is.
+///
+/// AniDB percent-escapes characters in the canonical filename that aren't safe in a URL,
+/// e.g. `%26` for `&`.
+pub fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(high), Some(low)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Undo AniDB's escaping of text fields: newlines are sent as `<br />`, and apostrophes as
+/// backticks, since `'` separates list entries.
+///
+/// AniDB also replaces `|` with `/`, but that can't be told apart from a real `/`.
+pub fn unescape_field(raw: &str) -> String {
+    raw.replace("<br />", "\n").replace('`', "'")
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("Tom%20%26%20Jerry.mkv"), "Tom & Jerry.mkv");
+    }
+
+    #[test]
+    fn test_percent_decode_utf8() {
+        assert_eq!(percent_decode("%E9%AD%94%E6%B3%95.mkv"), "魔法.mkv");
+    }
+
+    #[test]
+    fn test_unescape_field() {
+        assert_eq!(
+            unescape_field("First line<br />Second line"),
+            "First line\nSecond line"
+        );
+        assert_eq!(
+            unescape_field("Kiki`s Delivery Service"),
+            "Kiki's Delivery Service"
+        );
+        assert_eq!(unescape_field("Plain"), "Plain");
+    }
+
+    #[test]
+    fn test_percent_decode_invalid() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("50%zz.mkv"), "50%zz.mkv");
+    }
+}
@@ -1,23 +1,45 @@
 extern crate crypto;
+extern crate encoding_rs;
+extern crate flate2;
+#[macro_use]
+extern crate log;
+extern crate rand;
+extern crate rayon;
 extern crate rusqlite;
+#[cfg(feature = "serde")]
+extern crate serde;
 
+mod builder;
 mod cache;
+pub mod crc32;
 mod cutil;
 pub mod ed2k;
+mod encryption;
 mod errors;
+mod escape;
 pub mod md4;
+pub mod protocol;
 
 pub use errors::{AnidbError, Result};
-use std::net::{SocketAddr, ToSocketAddrs};
+pub use protocol::ReplyCode;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::path::PathBuf;
-use std::str;
+use std::str::FromStr;
 use std::thread;
 use std::time::{Duration, Instant};
 
 use std::net::UdpSocket;
 
-use cache::Cache;
+use rand::Rng;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+pub use builder::AnidbBuilder;
+pub use cache::{Cache, CacheBackend, MemoryCache};
 use ed2k::Ed2kHash;
+pub use protocol::FileMask;
 
 pub struct Anidb {
     pub socket: UdpSocket,
@@ -28,9 +50,73 @@ pub struct Anidb {
     /// Don't override, Anidb will ban you.
     pub last_send: Instant,
     pub ratelimit: Duration,
+    /// Random extra delay, up to this much, added on top of `ratelimit`. Spreads out
+    /// clients that would otherwise all start at the same time, e.g. from cron.
+    pub jitter: Duration,
+    /// Allow short bursts of messages faster than `ratelimit`, as AniDB does, and earn them
+    /// back by staying idle. A busy reply ends the burst. Sustained traffic still goes at
+    /// `ratelimit`. Off by default; `ratelimit` is then a fixed interval.
+    pub adaptive_ratelimit: bool,
+    /// How many fast messages the adaptive rate limit has left to send.
+    adaptive_burst: u32,
+
+    /// Fail on replies that aren't valid UTF-8, instead of decoding them as Windows-1252,
+    /// AniDB's older encoding. Off by default.
+    pub strict_encoding: bool,
+
+    /// Ask AniDB to echo the size and ed2k hash in FILE replies, and check them against the
+    /// query. Guards against a reply being matched up with the wrong request.
+    pub verify_echo: bool,
+
+    /// How many times to retry a command that got `601 ANIDB OUT OF SERVICE` or `602 SERVER
+    /// BUSY`, before giving up and returning the reply.
+    pub max_retries: u32,
+    /// How long to wait before the first retry. Each retry waits twice as long as the one
+    /// before, up to half an hour.
+    pub retry_delay: Duration,
+
+    /// The client name and version sent on login. AniDB wants every client registered
+    /// under its own name, and may ban a shared one, so real clients should set these to
+    /// their own. The defaults are this library's.
+    pub client_name: String,
+    pub client_version: u32,
+
+    /// Replies longer than this many bytes are rejected, rather than buffered. Defaults to
+    /// the largest possible UDP payload, so nothing AniDB sends is ever cut short; AniDB's
+    /// packets usually fit in the ~1400 byte MTU, but large ANIME replies may not.
+    pub max_reply_size: usize,
+
+    /// Ask AniDB to deflate large replies, by sending `comp=1` with AUTH. Uncompressed
+    /// replies are still understood, so this is safe to leave on. Set it before logging in.
+    pub compression: bool,
 
     /// API cache.
-    pub cache: Cache,
+    pub cache: Box<dyn CacheBackend>,
+
+    /// Store the session key in the cache, and reuse a recent one instead of logging in
+    /// again, e.g. from an earlier run. AniDB ties a session to the client's address and
+    /// port, so a stored session is only reused from the same local address; that needs a
+    /// fixed port, from `with_bind_address`. If AniDB rejects it anyway, e.g. because it
+    /// timed out, this logs in again by itself. Off by default; encrypted sessions are never
+    /// stored.
+    ///
+    /// The key is stored in plaintext in the cache directory, so anyone who can read it can
+    /// use the session from that address. Its half-hour lifetime is only checked here, when
+    /// reading it back; the cache doesn't delete it on its own.
+    pub persist_session: bool,
+    /// What `login` was given, for logging in again when AniDB drops the session.
+    credentials: Option<(String, String)>,
+
+    /// After a `555 BANNED` reply, nothing is sent until this time, and every call fails
+    /// with `AnidbError::Banned` straight away.
+    pub banned_until: Option<Instant>,
+    ban_reason: String,
+
+    /// The tag of the last command sent.
+    next_tag: u32,
+
+    /// Set by `enable_encryption`, for the rest of the session.
+    encryption_key: Option<encryption::Key>,
 }
 
 #[derive(Debug)]
@@ -39,37 +125,383 @@ pub struct ServerReply {
     pub data: String,
 }
 
-#[derive(Debug)]
+impl ServerReply {
+    /// The data records of the reply, i.e. every line after the status line, each split into
+    /// its `|`-separated fields.
+    pub fn records<'a>(&'a self) -> impl Iterator<Item = Vec<&'a str>> + 'a {
+        self.data
+            .lines()
+            .skip(1)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split('|').collect())
+    }
+
+    /// The code, as a `ReplyCode`.
+    pub fn reply_code(&self) -> ReplyCode {
+        ReplyCode::from_i32(self.code)
+    }
+
+    /// The fields of the first data record, for replies that only have one.
+    pub fn fields(&self) -> Vec<&str> {
+        self.records().next().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct File {
     pub fid: u32,
     pub aid: u32,
     pub eid: u32,
     pub gid: u32,
+    /// Other episodes the file covers, for multi-episode releases, as (eid, percentage of
+    /// that episode covered).
+    pub other_episodes: Vec<(u32, u8)>,
+    /// Bitfield of `FILE_STATE_*` flags.
+    pub state: u16,
+    // The fields below are `None` if they weren't asked for, or AniDB doesn't know them.
     /// "Canonical" filename, as per AniDB.
-    pub filename: String,
-    pub total_eps: u32,
-    pub highest_ep: u32,
+    pub filename: Option<String>,
+    pub total_eps: Option<u32>,
+    pub highest_ep: Option<u32>,
+    pub year: Option<String>,
+    pub typ: Option<String>,
+    pub series_romaji: Option<String>,
+    pub series_english: Option<String>,
+    /// Other titles for the series, e.g. in the original script.
+    pub series_other: Vec<String>,
+    pub series_short: Option<String>,
+    /// The episode number can be non-numeric, e.g. for specials.
+    pub ep_number: Option<String>,
+    pub ep_name: Option<String>,
+    pub ep_romaji: Option<String>,
+    pub group_name: Option<String>,
+    pub group_short: Option<String>,
+}
+
+/// Series-level data about an anime.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Anime {
+    pub aid: u32,
     pub year: String,
     pub typ: String,
-    pub series_romaji: String,
-    pub series_english: String,
-    pub series_other: String,
-    pub series_short: String,
+    pub romaji_name: String,
+    pub english_name: String,
+    pub episode_count: u32,
+    /// When the first episode aired, in seconds since the epoch. Zero if unknown.
+    pub air_date: u64,
+}
+
+/// Data about a single episode.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Episode {
+    pub eid: u32,
+    pub aid: u32,
+    /// Length in minutes.
+    pub length: u32,
+    /// Average rating, times 100.
+    pub rating: u32,
+    pub votes: u32,
     /// The episode number can be non-numeric, e.g. for specials.
     pub ep_number: String,
-    pub ep_name: String,
-    pub ep_romaji: String,
-    pub group_name: String,
-    pub group_short: String,
+    pub name_english: String,
+    pub name_romaji: String,
+    pub name_kanji: String,
+    /// When the episode aired, in seconds since the epoch. Zero if unknown.
+    pub aired: u64,
 }
 
+/// Data about a release group.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Group {
+    pub gid: u32,
+    pub name: String,
+    pub short_name: String,
+    pub irc_channel: String,
+    pub irc_server: String,
+    pub url: String,
+}
+
+/// Where a file in a user's mylist is stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MylistState {
+    Unknown = 0,
+    /// On a local hard drive.
+    Internal = 1,
+    /// On external media, e.g. CD or DVD.
+    External = 2,
+    Deleted = 3,
+    /// On remote storage, e.g. a NAS.
+    Remote = 4,
+}
+
+/// Parses the names used in config files: `unknown`, `hdd`, `external`, `deleted` or
+/// `remote`.
+impl FromStr for MylistState {
+    type Err = AnidbError;
+
+    fn from_str(s: &str) -> Result<MylistState> {
+        match s {
+            "unknown" => Ok(MylistState::Unknown),
+            "hdd" => Ok(MylistState::Internal),
+            "external" => Ok(MylistState::External),
+            "deleted" => Ok(MylistState::Deleted),
+            "remote" => Ok(MylistState::Remote),
+            _ => Err(AnidbError::Error(format!(
+                "Unknown mylist state {:?}; expected unknown, hdd, external, deleted or remote",
+                s
+            ))),
+        }
+    }
+}
+
+/// An entry in the user's mylist, as returned by `Anidb::mylist_get`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MylistEntry {
+    pub lid: u32,
+    pub fid: u32,
+    pub state: MylistState,
+    /// When the file was marked as watched, in seconds since the Unix epoch, or `None` if it
+    /// hasn't been.
+    pub viewdate: Option<u64>,
+    /// Free-form text saying where the file is kept.
+    pub storage: String,
+}
+
+/// What a vote is for, with `Anidb::vote`. The id passed along with it is the anime's aid
+/// for `Anime` and `Episode`, and the group's gid for `Group`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoteType {
+    Anime,
+    Group,
+    /// An episode of the anime, by its episode number.
+    Episode {
+        epno: u32,
+    },
+}
+
+/// A pending notification, as listed by `Anidb::notify_list`. Fetch the details with
+/// `Anidb::notify_get`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NotifyRef {
+    /// `'M'` for a private message, `'N'` for new files of a tracked anime.
+    pub typ: char,
+    /// The message id, or for new files the aid.
+    pub id: u32,
+}
+
+/// The details of a notification, as returned by `Anidb::notify_get`. Dates are in seconds
+/// since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Notification {
+    /// A private message, from another user or from AniDB itself.
+    Message {
+        id: u32,
+        from_uid: u32,
+        from_name: String,
+        date: u64,
+        /// 0 for a normal message, 1 for an anonymous one, 2 for a system message and 3
+        /// for a moderator message.
+        kind: u32,
+        title: String,
+        body: String,
+    },
+    /// New files of an anime the user gets notifications for.
+    NewFiles {
+        aid: u32,
+        /// 0 for all files, 1 for new files, 2 for new groups and 3 for completions.
+        kind: u32,
+        count: u32,
+        date: u64,
+        anime_name: String,
+        fids: Vec<u32>,
+    },
+}
+
+/// Result of adding a file to mylist.
+#[derive(Debug, PartialEq)]
+pub enum MylistOutcome {
+    /// A new mylist entry was created, with the given lid.
+    Added(u32),
+    /// The file was already in mylist, under the given lid.
+    AlreadyPresent(u32),
+}
+
+impl MylistOutcome {
+    /// The lid of the mylist entry, whether it's new or not.
+    pub fn lid(&self) -> u32 {
+        match *self {
+            MylistOutcome::Added(lid) | MylistOutcome::AlreadyPresent(lid) => lid,
+        }
+    }
+}
+
+/// Outcome of logging in.
+#[derive(Debug)]
+pub struct LoginResult {
+    pub session_id: String,
+    /// True if an existing session was reused, rather than doing a fresh AUTH.
+    pub reused: bool,
+    /// AniDB accepted the login, but says there's a newer version of this client.
+    pub new_version_available: bool,
+}
+
+/// The file's CRC32 matches the official one.
+pub const FILE_STATE_CRC_OK: u16 = 1;
+/// The file's CRC32 doesn't match the official one.
+pub const FILE_STATE_CRC_ERR: u16 = 2;
+/// The file is version 2 of the release.
+pub const FILE_STATE_V2: u16 = 4;
+/// The file is version 3 of the release.
+pub const FILE_STATE_V3: u16 = 8;
+/// The file is version 4 of the release.
+pub const FILE_STATE_V4: u16 = 16;
+/// The file is version 5 of the release.
+pub const FILE_STATE_V5: u16 = 32;
+/// The file is uncensored.
+pub const FILE_STATE_UNCENSORED: u16 = 64;
+/// The file is censored.
+pub const FILE_STATE_CENSORED: u16 = 128;
+
+impl File {
+    /// Release version of the file, e.g. 2 for a v2 re-release. Files that aren't marked
+    /// with a version are version 1.
+    pub fn version(&self) -> u32 {
+        if self.state & FILE_STATE_V5 != 0 {
+            5
+        } else if self.state & FILE_STATE_V4 != 0 {
+            4
+        } else if self.state & FILE_STATE_V3 != 0 {
+            3
+        } else if self.state & FILE_STATE_V2 != 0 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// The years the series ran, from `year`, which is either "2015" or a range like
+    /// "2016-2019". The end year is `None` for a single year, or if it isn't known yet.
+    ///
+    /// Returns `None` if the year is unknown or unparseable.
+    pub fn year_range(&self) -> Option<(u32, Option<u32>)> {
+        let mut parts = self.year.as_ref()?.trim().splitn(2, '-');
+        let start = parts.next()?.trim().parse().ok()?;
+        match parts.next().map(str::trim) {
+            None | Some("") => Some((start, None)),
+            Some(end) => Some((start, Some(end.parse().ok()?))),
+        }
+    }
+
+    /// The year the series started, for sorting chronologically.
+    pub fn sort_year(&self) -> Option<u32> {
+        self.year_range().map(|(start, _)| start)
+    }
+}
+
+/// A one-line summary, like "Little Witch Academia (2017) - 01 - A New Beginning [Asenshi]".
+/// Unknown fields are left out.
+impl fmt::Display for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts: Vec<&str> = [&self.series_romaji, &self.ep_number, &self.ep_name]
+            .iter()
+            .filter_map(|part| part.as_ref().map(String::as_str))
+            .collect();
+        if parts.is_empty() {
+            write!(f, "fid {}", self.fid)?;
+        } else {
+            write!(f, "{}", parts.join(" - "))?;
+        }
+        if let Some(ref group_short) = self.group_short {
+            write!(f, " [{}]", group_short)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name and version this library is registered under on AniDB.
+const DEFAULT_CLIENT_NAME: &str = "anidbrs";
+const DEFAULT_CLIENT_VERSION: u32 = 1;
+
+/// The largest possible UDP payload, over IPv4.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// How many times to resend a message that got an empty reply, before giving up.
+const EMPTY_REPLY_RETRIES: u32 = 3;
+
+/// How long to wait for a reply, by default. UDP is lossy, so a reply may never come.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times to resend a message that got no reply at all, before giving up.
+const TIMEOUT_RETRIES: u32 = 1;
+
+/// How many replies with the wrong tag to throw away while waiting for the right one.
+const STRAY_REPLY_LIMIT: u32 = 3;
+
+/// How long a stored session is reused for. AniDB drops sessions after about 35 minutes
+/// without traffic.
+const SESSION_LIFETIME: Duration = Duration::from_secs(30 * 60);
+
+/// The shortest interval the adaptive rate limit uses. AniDB allows short bursts at this
+/// rate, but not sustained traffic.
+const ADAPTIVE_MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many messages in a row the adaptive rate limit sends at `ADAPTIVE_MIN_INTERVAL`.
+const ADAPTIVE_BURST: u32 = 10;
+
+/// The longest a retry waits, however many retries came before it.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
 pub enum Session {
     Disconnected,
     Pending { user: String, pwd: String },
     Connected(String),
 }
 
+/// Passwords and session keys are redacted, so they don't end up in logs or panics.
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Session::Disconnected => write!(f, "Disconnected"),
+            Session::Pending { ref user, .. } => f
+                .debug_struct("Pending")
+                .field("user", user)
+                .field("pwd", &"***")
+                .finish(),
+            Session::Connected(_) => f.debug_tuple("Connected").field(&"***").finish(),
+        }
+    }
+}
+
+impl fmt::Debug for Anidb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Anidb")
+            .field("address", &self.address)
+            .field("session", &self.session)
+            .field("ratelimit", &self.ratelimit)
+            .field("jitter", &self.jitter)
+            .field("adaptive_ratelimit", &self.adaptive_ratelimit)
+            .field("verify_echo", &self.verify_echo)
+            .field("max_retries", &self.max_retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("client_name", &self.client_name)
+            .field("client_version", &self.client_version)
+            .field("max_reply_size", &self.max_reply_size)
+            .field("compression", &self.compression)
+            .field("persist_session", &self.persist_session)
+            .field("banned_until", &self.banned_until)
+            .field("encrypted", &self.encryption_key.is_some())
+            .finish()
+    }
+}
+
 impl Anidb {
     ///
     /// Creates a new instance of Anidb and makes a connection to the AniDB API server
@@ -79,19 +511,137 @@ impl Anidb {
     /// ```
     ///
     pub fn new<A: ToSocketAddrs>(addr: A, cache_dir: &PathBuf) -> Result<Anidb> {
-        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
-        socket.connect(&addr)?;
+        Self::with_cache(addr, Cache::new(cache_dir)?)
+    }
+
+    /// Like `new`, but uses an already opened cache, e.g. one shared with other code, or a
+    /// `MemoryCache` to stay off the disk.
+    pub fn with_cache<A: ToSocketAddrs, C: CacheBackend + 'static>(
+        addr: A,
+        cache: C,
+    ) -> Result<Anidb> {
+        Self::open(addr, None, Box::new(cache))
+    }
+
+    /// Like `with_cache`, but sends from a specific local address, e.g. to pick the network
+    /// interface, or to use IPv6. Use port 0 to let the system pick the port.
+    ///
+    /// Only server addresses of the same family, IPv4 or IPv6, are tried.
+    pub fn with_bind_address<A: ToSocketAddrs, C: CacheBackend + 'static>(
+        addr: A,
+        bind: SocketAddr,
+        cache: C,
+    ) -> Result<Anidb> {
+        Self::open(addr, Some(bind), Box::new(cache))
+    }
+
+    fn open<A: ToSocketAddrs>(
+        addr: A,
+        bind: Option<SocketAddr>,
+        cache: Box<dyn CacheBackend>,
+    ) -> Result<Anidb> {
+        let addresses: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let address = match (pick_address(&addresses, bind), bind) {
+            (Some(address), _) => address,
+            (None, Some(_)) => {
+                return Err(AnidbError::StaticError(
+                    "The server has no address of the same family as the bind address",
+                ))
+            }
+            (None, None) => {
+                return Err(AnidbError::StaticError(
+                    "Could not resolve the server address",
+                ))
+            }
+        };
+        let bind = bind.unwrap_or_else(|| match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        });
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(address)?;
+        socket.set_read_timeout(Some(DEFAULT_TIMEOUT))?;
 
         Ok(Anidb {
             socket: socket,
-            address: addr.to_socket_addrs().unwrap().next().unwrap(),
+            address,
             session: Session::Disconnected,
             last_send: Instant::now(),
             ratelimit: Duration::from_secs(4),
-            cache: Cache::new(cache_dir).expect("Cache creation failed"),
+            jitter: Duration::from_secs(0),
+            adaptive_ratelimit: false,
+            adaptive_burst: ADAPTIVE_BURST,
+            strict_encoding: false,
+            verify_echo: false,
+            max_retries: 3,
+            retry_delay: Duration::from_secs(30),
+            client_name: DEFAULT_CLIENT_NAME.to_owned(),
+            client_version: DEFAULT_CLIENT_VERSION,
+            max_reply_size: MAX_DATAGRAM_SIZE,
+            compression: false,
+            cache,
+            persist_session: false,
+            credentials: None,
+            banned_until: None,
+            ban_reason: String::new(),
+            next_tag: 0,
+            encryption_key: None,
         })
     }
 
+    /// How long to wait for a reply before giving up with `AnidbError::Timeout`. The
+    /// message is sent once more before that. Defaults to 10 seconds; can't be zero.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        Ok(())
+    }
+
+    /// How long cached replies are used for, before fetching them again.
+    pub fn cache_ttl(&self) -> Duration {
+        self.cache.max_age()
+    }
+
+    pub fn set_cache_ttl(&mut self, ttl: Duration) {
+        self.cache.set_max_age(ttl);
+    }
+
+    /// Forget every cached reply, so everything is fetched from AniDB again, e.g. after
+    /// AniDB has corrected its data. File hashes are kept.
+    pub fn clear_cache(&mut self) -> Result<()> {
+        self.cache.clear()
+    }
+
+    /// Forget the cached replies older than `older_than`, returning how many there were.
+    pub fn prune_cache(&mut self, older_than: Duration) -> Result<usize> {
+        self.cache.prune(older_than)
+    }
+
+    /// When the last message was sent.
+    pub fn last_send(&self) -> Instant {
+        self.last_send
+    }
+
+    /// The minimum time between messages.
+    pub fn ratelimit(&self) -> Duration {
+        self.ratelimit
+    }
+
+    /// The time between messages right now. That's `ratelimit`, unless the adaptive rate
+    /// limit is on and has some of its burst left.
+    pub fn send_interval(&self) -> Duration {
+        if self.adaptive_ratelimit && self.adaptive_burst > 0 {
+            ADAPTIVE_MIN_INTERVAL.min(self.ratelimit)
+        } else {
+            self.ratelimit
+        }
+    }
+
+    /// Whether sending a message right now would have to wait for the rate limit. Any jitter
+    /// comes on top of this.
+    pub fn is_rate_limited(&self) -> bool {
+        self.last_send.elapsed() < self.send_interval()
+    }
+
     /// Login the user to AniDB. You need to supply a user/pass that you have
     /// registered at https://anidb.net/
     ///
@@ -106,6 +656,7 @@ impl Anidb {
     /// ```
     ///
     pub fn login(&mut self, username: &str, password: &str) -> Result<()> {
+        self.credentials = Some((username.to_owned(), password.to_owned()));
         self.session = Session::Pending {
             user: username.to_owned(),
             pwd: password.to_owned(),
@@ -113,246 +664,1429 @@ impl Anidb {
         Ok(())
     }
 
+    /// Check that AniDB is reachable. Needs no session, and is never cached, so a long
+    /// running tool can call it every few minutes to keep a NAT mapping alive.
+    pub fn ping(&mut self) -> Result<()> {
+        let reply = self.send_wait_reply(&protocol::format_ping())?;
+        protocol::parse_ping(&reply)
+    }
+
+    /// The version of the AniDB server. Needs no session, and is never cached.
+    pub fn server_version(&mut self) -> Result<String> {
+        let reply = self.send_wait_reply(&protocol::format_version())?;
+        protocol::parse_version(&reply)
+    }
+
+    /// How long the AniDB server has been up. Logs in if needed, and is never cached.
+    pub fn server_uptime(&mut self) -> Result<Duration> {
+        let reply = self.call(&protocol::format_uptime())?;
+        protocol::parse_uptime(&reply)
+    }
+
+    /// Encrypt the session with the API key set in the user's AniDB profile. Call this after
+    /// `login`, but before the first query; AniDB only allows it before AUTH.
+    ///
+    /// Fails with `ErrorCode(309, ..)` if the user hasn't defined an API key, in which case
+    /// the session carries on unencrypted. Encryption ends with `logout`.
+    pub fn enable_encryption(&mut self, api_key: &str) -> Result<()> {
+        let encrypt_cmd = match self.session {
+            Session::Pending { ref user, .. } => protocol::format_encrypt(user),
+            Session::Connected(_) => {
+                return Err(AnidbError::StaticError(
+                    "Encryption must be enabled before logging in",
+                ))
+            }
+            Session::Disconnected => {
+                return Err(AnidbError::StaticError(
+                    "Call login before enabling encryption",
+                ))
+            }
+        };
+        let reply = self.send_wait_reply(&encrypt_cmd)?;
+        let salt = protocol::parse_encrypt(&reply)?;
+        self.encryption_key = Some(encryption::derive_key(api_key, &salt));
+        Ok(())
+    }
+
     /// Explicitly log out, e.g. to login as a different user.
     pub fn logout(&mut self) -> Result<()> {
         // TODO: Non-lexical lifetimes will let us simplify this.
         let logout_cmd = match self.session {
-            Session::Connected(ref session) => Self::format_logout_string(session),
+            Session::Connected(ref session) => protocol::format_logout(session),
             _ => "".to_owned(),
         };
+        // Forget the stored session first, so it isn't reused after a failed LOGOUT either.
+        if let Some((ref user, _)) = self.credentials {
+            if let Err(err) = self.cache.clear_session(user) {
+                warn!("Clearing the stored session: {}", err);
+            }
+        }
         if logout_cmd != "" {
             let reply = self.send_wait_reply(&logout_cmd)?;
-            println!("Reply from server {}", reply.data);
+            debug!("Reply from server {}", reply.data);
         }
         self.session = Session::Disconnected;
+        self.encryption_key = None;
+        self.credentials = None;
         Ok(())
     }
 
+    /// Whether there's a session, without sending anything. Credentials that haven't been
+    /// used yet don't count, and AniDB may still have expired the session on its side.
+    pub fn is_connected(&self) -> bool {
+        self.session_id().is_some()
+    }
+
+    /// The current session key, if logged in.
+    pub fn session_id(&self) -> Option<&str> {
+        match self.session {
+            Session::Connected(ref session) => Some(session),
+            _ => None,
+        }
+    }
+
     /// Search for a file, by hash.
+    ///
+    /// If the hash matches several files, this returns `AnidbError::MultipleFiles` listing
+    /// their fids, which can then be resolved with `file_from_fid`.
+    ///
+    /// Cache hits are answered without logging in or touching the network at all, so
+    /// previously seen files can be looked up offline.
     pub fn file_from_hash(&mut self, hash: &Ed2kHash) -> Result<File> {
-        let file_str = Self::format_file_hash_str(hash);
+        self.file_from_hash_with_mask(hash, &FileMask::default())
+    }
+
+    /// The fids of every file matching a hash. Usually there's one, but a hash can match
+    /// several deprecated or variant entries; resolve them with `file_from_fid`.
+    ///
+    /// Returns an empty list if AniDB doesn't know the hash.
+    pub fn files_from_hash(&mut self, hash: &Ed2kHash) -> Result<Vec<u32>> {
+        match self.file_from_hash(hash) {
+            Ok(file) => Ok(vec![file.fid]),
+            Err(AnidbError::MultipleFiles(fids)) => Ok(fids),
+            Err(AnidbError::NoSuchFile) => Ok(Vec::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Look up many files at once. Each hash gets its own result, in the same order, so a
+    /// file AniDB doesn't know doesn't stop the rest.
+    ///
+    /// Hashes already in the cache are answered straight away; only the others wait for the
+    /// rate limit. After a ban, the remaining misses fail with `Banned` without being sent.
+    pub fn files_from_hashes(&mut self, hashes: &[Ed2kHash]) -> Vec<Result<File>> {
+        hashes
+            .iter()
+            .map(|hash| self.file_from_hash(hash))
+            .collect()
+    }
+
+    /// Like `file_from_hash`, but only asks for the fields set in `mask`. The rest are left
+    /// empty.
+    pub fn file_from_hash_with_mask(&mut self, hash: &Ed2kHash, mask: &FileMask) -> Result<File> {
+        let mask = self.echo_mask(mask);
+        let file_str = protocol::format_file(hash, &mask);
         let reply = self.call_cached(&file_str)?;
-        match reply.code {
-            322 => Err(AnidbError::Error("Found multiple files. Panic!".to_owned())),
-            320 => Err(AnidbError::NoSuchFile),
-            220 => {
-                let data = reply.data.split('\n').nth(1).expect("FILE format error");
-                let mut fields = data.split('|');
-                // The list of what we asked for.
-                // Currently that's statically determined by the query format.
-                let fid = fields.next().expect("fid not found");
-                let aid = fields.next().expect("aid not found");
-                let eid = fields.next().expect("eid not found");
-                let gid = fields.next().expect("gid not found");
-                let filename = fields.next().expect("filename not found");
-                let total_eps = fields.next().expect("total_eps not found");
-                let highest_ep = fields.next().expect("highest_ep not found");
-                let year = fields.next().expect("year not found");
-                let typ = fields.next().expect("typ not found");
-                let series_romaji = fields.next().expect("series_romaji not found");
-                let series_english = fields.next().expect("series_english not found");
-                let series_other = fields.next().expect("series_other not found");
-                let series_short = fields.next().expect("series_short not found");
-                let ep_number = fields.next().expect("ep_number not found");
-                let ep_name = fields.next().expect("ep_name not found");
-                let ep_romaji = fields.next().expect("ep_romaji not found");
-                let group_name = fields.next().expect("group_name not found");
-                let group_short = fields.next().expect("group_short not found");
-
-                Ok(File {
-                    fid: fid.parse().expect("fid"),
-                    aid: aid.parse().expect("aid"),
-                    eid: eid.parse().expect("eid"),
-                    gid: gid.parse().expect("gid"),
-                    filename: filename.to_owned(),
-                    total_eps: total_eps.parse().expect("total_eps"),
-                    highest_ep: highest_ep.parse().expect("highest"),
-                    year: year.to_owned(),
-                    typ: typ.to_owned(),
-                    series_romaji: series_romaji.to_owned(),
-                    series_english: series_english.to_owned(),
-                    series_other: series_other.to_owned(),
-                    series_short: series_short.to_owned(),
-                    ep_number: ep_number.to_owned(),
-                    ep_name: ep_name.to_owned(),
-                    ep_romaji: ep_romaji.to_owned(),
-                    group_name: group_name.to_owned(),
-                    group_short: group_short.to_owned(),
-                })
-            }
-            code => Err(AnidbError::Error(format!("Unexpected code {}", code))),
+        protocol::parse_file(&reply, &mask, self.echo_for(hash))
+    }
+
+    /// Like `file_from_hash`, but bypasses the cache and fetches fresh data from AniDB, e.g.
+    /// after the file was edited on the website.
+    ///
+    /// The fresh reply replaces whatever was cached for this file. If there's no reply, the
+    /// cached one is kept.
+    pub fn refresh_file(&mut self, hash: &Ed2kHash) -> Result<File> {
+        let mask = self.echo_mask(&FileMask::default());
+        let file_str = protocol::format_file(hash, &mask);
+        let reply = self.call_fresh(&file_str)?;
+        protocol::parse_file(&reply, &mask, self.echo_for(hash))
+    }
+
+    /// Look up a file by its AniDB file id, e.g. one from `files_from_hash`.
+    ///
+    /// If there's no such file, this returns `AnidbError::NoSuchFile`.
+    pub fn file_from_fid(&mut self, fid: u32) -> Result<File> {
+        let mask = FileMask::default();
+        let file_str = protocol::format_file_fid(fid, &mask);
+        let reply = self.call_cached(&file_str)?;
+        protocol::parse_file(&reply, &mask, None)
+    }
+
+    /// Look up a file by the anime, release group and episode it's for, such as when AniDB
+    /// doesn't know the file's hash yet. `epno` is the episode number as AniDB writes it,
+    /// such as "5" or "S1".
+    ///
+    /// If the group released several files for the episode, this fails with
+    /// `MultipleFiles`.
+    pub fn file_by_anime_episode(&mut self, aid: u32, group: u32, epno: &str) -> Result<File> {
+        let mask = FileMask::default();
+        let file_str = protocol::format_file_episode(aid, group, epno, &mask);
+        let reply = self.call_cached(&file_str)?;
+        protocol::parse_file(&reply, &mask, None)
+    }
+
+    /// Look up the file behind a mylist entry, by its mylist id.
+    pub fn file_from_lid(&mut self, lid: u32) -> Result<File> {
+        let mylist_str = protocol::format_mylist_lid(lid);
+        // The entry's state may change, but which file it's for doesn't, so this is safe
+        // to cache.
+        let reply = self.call_cached(&mylist_str)?;
+        let fid = protocol::parse_mylist_fid(&reply)?;
+        self.file_from_fid(fid)
+    }
+
+    /// Look up an anime by its AniDB anime id.
+    ///
+    /// If there's no such anime, this returns `AnidbError::NoSuchFile`.
+    pub fn anime_from_aid(&mut self, aid: u32) -> Result<Anime> {
+        let anime_str = protocol::format_anime(aid);
+        let reply = self.call_cached(&anime_str)?;
+        protocol::parse_anime(&reply)
+    }
+
+    /// Like `anime_from_aid`, but fetches fresh data; see `refresh_file`.
+    pub fn refresh_anime(&mut self, aid: u32) -> Result<Anime> {
+        let reply = self.call_fresh(&protocol::format_anime(aid))?;
+        protocol::parse_anime(&reply)
+    }
+
+    /// Look up an episode by its AniDB episode id.
+    ///
+    /// If there's no such episode, this returns `AnidbError::NoSuchFile`.
+    pub fn episode_from_eid(&mut self, eid: u32) -> Result<Episode> {
+        let episode_str = protocol::format_episode(eid);
+        let reply = self.call_cached(&episode_str)?;
+        protocol::parse_episode(&reply)
+    }
+
+    /// Like `episode_from_eid`, but fetches fresh data; see `refresh_file`.
+    pub fn refresh_episode(&mut self, eid: u32) -> Result<Episode> {
+        let reply = self.call_fresh(&protocol::format_episode(eid))?;
+        protocol::parse_episode(&reply)
+    }
+
+    /// Look up a release group by its AniDB group id.
+    ///
+    /// If there's no such group, this returns `AnidbError::NoSuchFile`.
+    pub fn group_from_gid(&mut self, gid: u32) -> Result<Group> {
+        let group_str = protocol::format_group(gid);
+        let reply = self.call_cached(&group_str)?;
+        protocol::parse_group(&reply)
+    }
+
+    /// Like `group_from_gid`, but fetches fresh data; see `refresh_file`.
+    pub fn refresh_group(&mut self, gid: u32) -> Result<Group> {
+        let reply = self.call_fresh(&protocol::format_group(gid))?;
+        protocol::parse_group(&reply)
+    }
+
+    /// The mask, plus the echoed size and hash if we're verifying them.
+    fn echo_mask(&self, mask: &FileMask) -> FileMask {
+        let mut mask = mask.clone();
+        if self.verify_echo {
+            mask.size = true;
+            mask.ed2k = true;
         }
+        mask
     }
 
-    fn assert_session(&mut self) -> Result<String> {
+    fn echo_for<'a>(&self, hash: &'a Ed2kHash) -> Option<&'a Ed2kHash> {
+        if self.verify_echo {
+            Some(hash)
+        } else {
+            None
+        }
+    }
+
+    /// Look up a file by hash and add it to mylist, in one go.
+    ///
+    /// This is the common "identify this file and mark that I have it" workflow. A file that's
+    /// already in mylist is not an error; check the returned `MylistOutcome` instead.
+    pub fn identify_and_add(
+        &mut self,
+        hash: &Ed2kHash,
+        state: MylistState,
+    ) -> Result<(File, MylistOutcome)> {
+        let file = self.file_from_hash(hash)?;
+        let outcome = self.mylist_add(hash, state, false)?;
+        Ok((file, outcome))
+    }
+
+    /// Add a file to mylist, by hash, optionally marking it as watched. A file that's already
+    /// in mylist is not an error; either way, the outcome holds the entry's lid.
+    pub fn mylist_add(
+        &mut self,
+        hash: &Ed2kHash,
+        state: MylistState,
+        viewed: bool,
+    ) -> Result<MylistOutcome> {
+        let mylist_str = protocol::format_mylist_add(hash, state, viewed);
+        // Not cached, since this modifies the mylist.
+        let reply = self.call(&mylist_str)?;
+        // Whatever `mylist_get` cached for the file is out of date now.
+        self.cache.invalidate(&protocol::format_mylist(hash))?;
+        protocol::parse_mylist_add(&reply)
+    }
+
+    /// Look up the mylist entry for a file, by hash, to see whether it's in mylist and in
+    /// what state.
+    ///
+    /// Fails with `NoSuchFile` if the file isn't in mylist, and with `MultipleMylistEntries`
+    /// if the hash matches several entries. The entry is cached like other lookups, except
+    /// that `mylist_add` clears it.
+    pub fn mylist_get(&mut self, hash: &Ed2kHash) -> Result<MylistEntry> {
+        let mylist_str = protocol::format_mylist(hash);
+        let reply = self.call_cached(&mylist_str)?;
+        protocol::parse_mylist(&reply)
+    }
+
+    /// Rate an anime, release group or episode. `id` is the aid for an anime or episode and
+    /// the gid for a group. `value` is the rating times 100, from 100 for 1.00 to 1000 for
+    /// 10.00.
+    ///
+    /// Both a new vote and a changed one are fine; if AniDB reports the vote as already
+    /// there instead, this fails with `AlreadyVoted` and its value. Never cached.
+    pub fn vote(&mut self, typ: VoteType, id: u32, value: u16) -> Result<()> {
+        if !(100..=1000).contains(&value) {
+            return Err(AnidbError::StaticError(
+                "Votes go from 100 to 1000, the rating times 100",
+            ));
+        }
+        let reply = self.call(&protocol::format_vote(typ, id, value))?;
+        protocol::parse_vote(&reply)
+    }
+
+    /// List the user's pending notifications and private messages.
+    ///
+    /// Notifications come and go, so this is never cached.
+    pub fn notify_list(&mut self) -> Result<Vec<NotifyRef>> {
+        let reply = self.call(&protocol::format_notify_list())?;
+        protocol::parse_notify_list(&reply)
+    }
+
+    /// Fetch a notification listed by `notify_list`: a private message if `typ` is `'M'`,
+    /// or new files of an anime if it's `'N'`. Fails with `NoSuchFile` if it's gone.
+    ///
+    /// Never cached, like `notify_list`.
+    pub fn notify_get(&mut self, typ: char, id: u32) -> Result<Notification> {
+        let reply = self.call(&protocol::format_notify_get(typ, id))?;
+        protocol::parse_notify_get(&reply)
+    }
+
+    /// Log in now, rather than waiting for the first command that needs a session.
+    ///
+    /// If there's already a session, it's reused.
+    pub fn connect(&mut self) -> Result<LoginResult> {
+        let stored = match self.session {
+            Session::Pending { ref user, .. } if self.persists_session() => {
+                // A cache that can't be read just means logging in again.
+                self.socket.local_addr().ok().and_then(|local| {
+                    self.cache
+                        .get_session(user, &local, SESSION_LIFETIME)
+                        .unwrap_or(None)
+                })
+            }
+            _ => None,
+        };
+        if let Some(session) = stored {
+            debug!("Reusing the stored session");
+            self.session = Session::Connected(session.clone());
+            return Ok(LoginResult {
+                session_id: session,
+                reused: true,
+                new_version_available: false,
+            });
+        }
         // TODO: Non-lexical lifetimes will let us simplify this.
         let login_cmd = match self.session {
             Session::Disconnected => String::new(),
             Session::Connected(_) => String::new(),
-            Session::Pending { ref user, ref pwd } => Self::format_login_string(user, pwd),
+            Session::Pending { ref user, ref pwd } => {
+                protocol::format_login(user, pwd, &self.client_name, self.client_version)
+            }
+        };
+        let login_cmd = if self.compression && !login_cmd.is_empty() {
+            login_cmd + "&comp=1"
+        } else {
+            login_cmd
         };
         if login_cmd != "" {
             let reply = self.send_wait_reply(&login_cmd)?;
-            println!("Reply from server {}", reply.data);
-            let session = Self::validate_auth_command(&reply)?;
-            self.session = Session::Connected(session);
+            // The reply holds the session key, so only the code is shown.
+            debug!("Reply from server {}", reply.code);
+            let session = protocol::validate_auth(&reply)?;
+            if reply.reply_code() == ReplyCode::LoginAcceptedNewVersion {
+                info!("AniDB reports a new version of this client is available");
+            }
+            if self.persists_session() {
+                if let Some((ref user, _)) = self.credentials {
+                    let stored = self
+                        .socket
+                        .local_addr()
+                        .map_err(AnidbError::from)
+                        .and_then(|local| self.cache.put_session(user, &local, &session));
+                    if let Err(err) = stored {
+                        warn!("Storing the session: {}", err);
+                    }
+                }
+            }
+            self.session = Session::Connected(session.clone());
+            self.request_utf8(&session);
+            return Ok(LoginResult {
+                session_id: session,
+                reused: false,
+                new_version_available: reply.reply_code() == ReplyCode::LoginAcceptedNewVersion,
+            });
         }
         match self.session {
-            Session::Connected(ref session) => Ok(session.clone()),
-            _ => unreachable!(),
+            Session::Connected(ref session) => Ok(LoginResult {
+                session_id: session.clone(),
+                reused: true,
+                new_version_available: false,
+            }),
+            _ => Err(AnidbError::StaticError("Not logged in")),
         }
     }
 
-    /// Validates that the auth command has a correct reply from the server
-    fn validate_auth_command(reply: &ServerReply) -> Result<String> {
-        if reply.code != 200 {
-            return Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned()));
+    /// Ask for UTF-8 replies, since AniDB otherwise may use an older encoding, which would
+    /// garble non-ASCII titles. If AniDB refuses or never answers, the session still works,
+    /// so that's only logged.
+    fn request_utf8(&mut self, session: &str) {
+        let message = protocol::add_param(&protocol::format_encoding("UTF8"), "s", session);
+        let result = self
+            .send_wait_reply(&message)
+            .and_then(|reply| protocol::parse_encoding(&reply));
+        if let Err(err) = result {
+            warn!(
+                "AniDB didn't switch to UTF-8, so titles may be garbled: {}",
+                err
+            );
         }
+    }
 
-        let v: Vec<&str> = reply.data.split(' ').collect();
+    fn assert_session(&mut self) -> Result<String> {
+        Ok(self.connect()?.session_id)
+    }
 
-        if v.len() != 3 {
-            return Err(AnidbError::Error(format!(
-                "Invalid AUTH reply: {} expceted 3 args",
-                reply.data
-            )));
+    fn persists_session(&self) -> bool {
+        self.persist_session && self.encryption_key.is_none()
+    }
+
+    /// Send a message and wait for the reply, sending it again if the reply is empty.
+    ///
+    /// While banned, this fails without sending anything.
+    fn send_wait_reply(&mut self, message: &str) -> Result<ServerReply> {
+        if let Some(until) = self.banned_until {
+            if Instant::now() < until {
+                return Err(AnidbError::Banned(self.ban_reason.clone()));
+            }
+            self.banned_until = None;
         }
+        // Every command is tagged, so its reply can be told apart from stray late replies to
+        // earlier ones. Resends keep the tag, since they're the same command.
+        self.next_tag = self.next_tag.wrapping_add(1);
+        let tag = format!("t{}", self.next_tag);
+        let message = protocol::add_param(message, "tag", &tag);
+        let mut retries = 0;
+        let mut timeouts = 0;
+        let reply = loop {
+            match self.send_wait_reply_once(&message, &tag) {
+                Err(AnidbError::EmptyReply) if retries < EMPTY_REPLY_RETRIES => retries += 1,
+                Err(AnidbError::Timeout) if timeouts < TIMEOUT_RETRIES => timeouts += 1,
+                result => break result?,
+            }
+        };
+        self.adapt_interval(reply.code);
+        if let Some((reason, duration)) = protocol::parse_ban(&reply) {
+            self.banned_until = Some(Instant::now() + duration);
+            warn!("Banned by AniDB for {:?}: {}", duration, reason);
+            self.ban_reason = reason.clone();
+            return Err(AnidbError::Banned(reason));
+        }
+        Ok(reply)
+    }
 
-        if v[1] != "LOGIN" || v[2] != "ACCEPTED\n" {
+    fn send_wait_reply_once(&mut self, message: &str, tag: &str) -> Result<ServerReply> {
+        let now = Instant::now();
+        let period = now.duration_since(self.last_send);
+        let delay = Self::send_delay(self.send_interval(), self.jitter);
+        if period < delay {
+            thread::sleep(delay - period);
+        }
+        let gap = self.last_send.elapsed();
+        self.spend_burst(gap);
+        self.last_send = Instant::now();
+        let datagram = match self.encryption_key {
+            Some(ref key) => encryption::encrypt(key, message.as_bytes()),
+            None => message.as_bytes().to_vec(),
+        };
+        self.socket.send(&datagram)?;
+        let mut strays = 0;
+        loop {
+            match self.recv_reply(tag) {
+                Err(AnidbError::TagMismatch { ref reply, .. }) if strays < STRAY_REPLY_LIMIT => {
+                    debug!("Discarding stray reply {:?}", reply);
+                    strays += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Receive one datagram, which should be the reply tagged with `tag`.
+    fn recv_reply(&mut self, tag: &str) -> Result<ServerReply> {
+        // One byte extra, so an oversized datagram fills the buffer instead of being
+        // silently truncated to fit.
+        let mut result = vec![0; self.max_reply_size + 1];
+        let len = match self.socket.recv(&mut result) {
+            // Which of the two a timeout gives depends on the platform.
+            Err(ref err)
+                if err.kind() == io::ErrorKind::WouldBlock
+                    || err.kind() == io::ErrorKind::TimedOut =>
+            {
+                return Err(AnidbError::Timeout)
+            }
+            len => len?,
+        };
+        if len > self.max_reply_size {
             return Err(AnidbError::Error(format!(
-                "Invalid AUTH reply: {} LOGIN ACCEPTED\\n expected",
-                reply.data
+                "Reply truncated: it's larger than the maximum of {} bytes",
+                self.max_reply_size
             )));
         }
+        if let Some(ref key) = self.encryption_key {
+            // An empty datagram is left for parse_tagged_reply to report.
+            if len > 0 {
+                let decrypted = encryption::decrypt(key, &result[..len])?;
+                return protocol::parse_tagged_reply(
+                    &decrypted,
+                    decrypted.len(),
+                    Some(tag),
+                    self.strict_encoding,
+                );
+            }
+        }
+        protocol::parse_tagged_reply(&result, len, Some(tag), self.strict_encoding)
+    }
 
-        Ok(v[0].to_owned())
+    /// Tune the adaptive rate limit to a reply: if AniDB is busy, the burst is over until
+    /// it's earned back.
+    fn adapt_interval(&mut self, code: i32) {
+        if self.adaptive_ratelimit && is_server_error(code) {
+            self.adaptive_burst = 0;
+        }
     }
 
-    /// Parse the reply from the server which is expected to be in xxx - format. If that is not the
-    /// case this function will return an error that the reply couldn't be parsed.
-    fn parse_reply(reply: &[u8], len: usize) -> Result<ServerReply> {
-        if len < 5 {
-            return Err(AnidbError::StaticError("Reply less than 5 chars"));
+    /// Count a message sent `gap` after the one before against the adaptive burst. Sending
+    /// sooner than `ratelimit` uses up one fast message, and every bit of idle time beyond
+    /// `ratelimit` that one would have saved earns one back.
+    fn spend_burst(&mut self, gap: Duration) {
+        if !self.adaptive_ratelimit || self.ratelimit <= ADAPTIVE_MIN_INTERVAL {
+            return;
+        }
+        if gap < self.ratelimit {
+            self.adaptive_burst = self.adaptive_burst.saturating_sub(1);
+        } else {
+            let saved = (self.ratelimit - ADAPTIVE_MIN_INTERVAL).as_millis();
+            let earned = ((gap - self.ratelimit).as_millis() / saved).min(ADAPTIVE_BURST as u128);
+            self.adaptive_burst = (self.adaptive_burst + earned as u32).min(ADAPTIVE_BURST);
         }
-        let code_str = str::from_utf8(&reply[0..3])?;
-        let code = code_str.parse::<i32>()?;
-        Ok(ServerReply {
-            code: code,
-            data: String::from_utf8_lossy(&reply[4..len]).into_owned(),
-        })
     }
 
-    fn send_wait_reply(&mut self, message: &str) -> Result<ServerReply> {
-        let now = Instant::now();
-        let period = now.duration_since(self.last_send);
-        if period < self.ratelimit {
-            thread::sleep(self.ratelimit - period);
+    /// How long to wait before retry number `retries`, counting from zero: twice as long as
+    /// the one before, but never more than `MAX_RETRY_DELAY`.
+    fn retry_backoff(retry_delay: Duration, retries: u32) -> Duration {
+        2u32.checked_pow(retries)
+            .and_then(|factor| retry_delay.checked_mul(factor))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
+
+    /// The jitter only ever lengthens the delay, so it can't push us over the rate limit.
+    fn send_delay(ratelimit: Duration, jitter: Duration) -> Duration {
+        if jitter == Duration::from_secs(0) {
+            return ratelimit;
         }
-        self.last_send = Instant::now();
-        let mut result = [0; 2048];
-        self.socket.send(message.as_bytes())?;
-        let len = self.socket.recv(&mut result)?;
-        Self::parse_reply(&result, len)
+        let max = jitter.as_millis() as u64;
+        ratelimit + Duration::from_millis(rand::thread_rng().gen_range(0, max + 1))
     }
 
     fn call_cached(&mut self, message: &str) -> Result<ServerReply> {
-        let cached = self.cache.get(message);
-        match cached {
-            Err(AnidbError::SqliteError(rusqlite::Error::QueryReturnedNoRows)) => {
-                self.call(message)
-            }
-            Err(err) => Err(err),
-            Ok(result) => Ok(result),
+        if let Some(cached) = self.cache.get(message)? {
+            return Ok(cached);
         }
+        self.call_fresh(message)
     }
 
-    fn call(&mut self, message: &str) -> Result<ServerReply> {
-        let s = self.assert_session()?;
-        let mws = format!("{}&s={}", message, s);
-        let reply = self.send_wait_reply(&mws)?;
-        println!("Reply from server {:?}", reply);
-        self.cache.put(message, &reply)?;
+    /// Send a lookup without reading the cache, but cache the reply. Commands that change
+    /// anything, like MYLISTADD, go through `call` instead, so they're never cached.
+    fn call_fresh(&mut self, message: &str) -> Result<ServerReply> {
+        let reply = self.call(message)?;
+        // Only answers are cached, including the "no such" ones. 5xx and 6xx errors are
+        // about this session or the server right now, so next time might go better.
+        if reply.code < 500 {
+            self.cache.put(message, &reply)?;
+        }
         Ok(reply)
     }
 
-    fn format_logout_string(session_id: &str) -> String {
-        format!("LOGOUT s={}", session_id)
+    /// Send a command in the current session, logging in first if needed.
+    ///
+    /// Commands that AniDB is too busy for are retried, backing off exponentially; see
+    /// `max_retries`. A ban is never retried.
+    fn call(&mut self, message: &str) -> Result<ServerReply> {
+        let mut retries = 0;
+        let mut relogged = false;
+        loop {
+            let s = self.assert_session()?;
+            let mws = protocol::add_param(message, "s", &s);
+            let reply = self.send_wait_reply(&mws)?;
+            debug!("Reply from server {:?}", reply);
+            // The session timed out, or was stored by another process. An encrypted session
+            // can't just log in again, since the encryption has to be set up before AUTH.
+            let dropped = matches!(
+                reply.reply_code(),
+                ReplyCode::LoginFirst | ReplyCode::InvalidSession
+            );
+            if dropped && !relogged && self.encryption_key.is_none() {
+                if let Some((user, pwd)) = self.credentials.clone() {
+                    info!(
+                        "AniDB dropped the session ({}); logging in again",
+                        reply.code
+                    );
+                    self.cache.clear_session(&user)?;
+                    self.session = Session::Pending { user, pwd };
+                    relogged = true;
+                    continue;
+                }
+            }
+            if !is_server_error(reply.code) || retries >= self.max_retries {
+                return Ok(reply);
+            }
+            let delay = Self::retry_backoff(self.retry_delay, retries);
+            warn!(
+                "AniDB replied {} {}; retrying in {:?}",
+                reply.code,
+                reply.data.lines().next().unwrap_or(""),
+                delay
+            );
+            thread::sleep(delay);
+            retries += 1;
+        }
+    }
+}
+
+/// Which of the server's addresses to talk to. With a bind address, that's the first of the
+/// same family; otherwise IPv4 is preferred, as it works on the most networks.
+fn pick_address(addresses: &[SocketAddr], bind: Option<SocketAddr>) -> Option<SocketAddr> {
+    match bind {
+        Some(bind) => addresses
+            .iter()
+            .find(|address| address.is_ipv4() == bind.is_ipv4())
+            .cloned(),
+        None => addresses
+            .iter()
+            .find(|address| address.is_ipv4())
+            .or_else(|| addresses.first())
+            .cloned(),
     }
+}
 
-    fn format_login_string(username: &str, password: &str) -> String {
-        format!(
-            "AUTH user={}&pass={}&protover=3&client=anidbrs&clientver=1",
-            username, password
-        )
+/// `601 ANIDB OUT OF SERVICE` and `602 SERVER BUSY`, which go away by themselves.
+fn is_server_error(code: i32) -> bool {
+    matches!(
+        ReplyCode::from_i32(code),
+        ReplyCode::OutOfService | ReplyCode::ServerBusy
+    )
+}
+
+#[cfg(test)]
+mod test_records {
+    use super::*;
+
+    #[test]
+    fn test_records_multiple() {
+        let reply = ServerReply {
+            code: 312,
+            data: "MULTIPLE MYLIST ENTRIES\nLittle Witch Academia|1|2\nFoo|3|4\n".to_owned(),
+        };
+        let records: Vec<Vec<&str>> = reply.records().collect();
+        assert_eq!(
+            records,
+            vec![
+                vec!["Little Witch Academia", "1", "2"],
+                vec!["Foo", "3", "4"],
+            ]
+        );
     }
 
-    fn format_file_hash_str(hash: &Ed2kHash) -> String {
-        format!(
-            "FILE size={}&ed2k={}&fmask=7000000100&amask=F0B8E0C0",
-            hash.size, hash.hex
-        )
+    #[test]
+    fn test_fields_no_data() {
+        let reply = ServerReply {
+            code: 320,
+            data: "NO SUCH FILE\n".to_owned(),
+        };
+        assert_eq!(reply.records().count(), 0);
+        assert!(reply.fields().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test_cache {
+    use super::*;
+    use std::env;
+
+    /// A logged-in client with an empty in-memory cache, and no server to talk to.
+    fn offline_db() -> Anidb {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let mut db = Anidb::with_cache(silent.local_addr().unwrap(), MemoryCache::new()).unwrap();
+        db.login("foo", "bar").unwrap();
+        db
+    }
+
+    /// A FILE reply for episode 1 of Little Witch Academia (2017).
+    fn lwa_file_reply() -> ServerReply {
+        ServerReply {
+            code: 220,
+            data: "FILE\n1879191|12235|183230|10435||5|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)|||lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_cached_lookup_needs_no_network() {
+        // Nothing ever answers on this socket, so any network traffic would time out.
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let cache_dir = env::temp_dir().join("anidb-rs-test-cached-lookup");
+        let mut db = Anidb::new(silent.local_addr().unwrap(), &cache_dir).unwrap();
+        db.socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.login("foo", "bar").unwrap();
+
+        let hash = Ed2kHash {
+            bin: [0; 16],
+            size: 365985588,
+            hex: "a5a3c7e9d2bd7b5f8e8e1c7c3a45e6b1".to_owned(),
+            alt_bin: None,
+            crc32: None,
+        };
+        let query = protocol::format_file(&hash, &FileMask::default());
+        let reply = lwa_file_reply();
+        db.cache.invalidate(&query).unwrap();
+        db.cache.put(&query, &reply).unwrap();
+
+        let file = db.file_from_hash(&hash).unwrap();
+        assert_eq!(file.fid, 1879191);
+        assert_eq!(file.version(), 2);
+        match db.session {
+            Session::Pending { .. } => (),
+            ref other => panic!("Expected no login, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_files_from_hash_multiple() {
+        let mut db = offline_db();
+
+        let hash = Ed2kHash::from_parts([1; 16], 1000, None);
+        let query = protocol::format_file(&hash, &FileMask::default());
+        let reply = ServerReply {
+            code: 322,
+            data: "MULTIPLE FILES FOUND\n1879191|1879192\n".to_owned(),
+        };
+        db.cache.put(&query, &reply).unwrap();
+
+        assert_eq!(db.files_from_hash(&hash).unwrap(), vec![1879191, 1879192]);
+    }
+
+    #[test]
+    fn test_files_from_hashes() {
+        let mut db = offline_db();
+
+        let known = Ed2kHash::from_parts([2; 16], 1000, None);
+        let query = protocol::format_file(&known, &FileMask::default());
+        let reply = lwa_file_reply();
+        db.cache.put(&query, &reply).unwrap();
+        let unknown = Ed2kHash::from_parts([3; 16], 1000, None);
+        let query = protocol::format_file(&unknown, &FileMask::default());
+        let reply = ServerReply {
+            code: 320,
+            data: "NO SUCH FILE\n".to_owned(),
+        };
+        db.cache.put(&query, &reply).unwrap();
+
+        let results = db.files_from_hashes(&[unknown, known]);
+        assert_eq!(results.len(), 2);
+        match results[0] {
+            Err(AnidbError::NoSuchFile) => {}
+            ref other => panic!("Expected NoSuchFile, got {:?}", other),
+        }
+        assert_eq!(results[1].as_ref().unwrap().fid, 1879191);
+    }
+
+    #[test]
+    fn test_file_from_fid() {
+        let mut db = offline_db();
+
+        let query = protocol::format_file_fid(1879191, &FileMask::default());
+        let reply = lwa_file_reply();
+        db.cache.put(&query, &reply).unwrap();
+        let file = db.file_from_fid(1879191).unwrap();
+        assert_eq!(file.fid, 1879191);
+        assert_eq!(file.group_short.unwrap(), "Asenshi");
+
+        let query = protocol::format_file_fid(1, &FileMask::default());
+        let reply = ServerReply {
+            code: 320,
+            data: "NO SUCH FILE\n".to_owned(),
+        };
+        db.cache.put(&query, &reply).unwrap();
+        match db.file_from_fid(1) {
+            Err(AnidbError::NoSuchFile) => {}
+            other => panic!("Expected NoSuchFile, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_by_anime_episode() {
+        let mut db = offline_db();
+
+        let query = protocol::format_file_episode(12235, 10435, "1", &FileMask::default());
+        let reply = lwa_file_reply();
+        db.cache.put(&query, &reply).unwrap();
+        let file = db.file_by_anime_episode(12235, 10435, "1").unwrap();
+        assert_eq!(file.fid, 1879191);
+        assert_eq!(file.ep_name.unwrap(), "A New Beginning");
+    }
+
+    #[test]
+    fn test_mylist_get() {
+        let mut db = offline_db();
+
+        let hash = Ed2kHash {
+            bin: [0; 16],
+            size: 365985588,
+            hex: "a5a3c7e9d2bd7b5f8e8e1c7c3a45e6b1".to_owned(),
+            alt_bin: None,
+            crc32: None,
+        };
+        let reply = ServerReply {
+            code: 221,
+            data: "MYLIST\n123456|1879191|183230|12235|10435|1498599583|1|0|||||1\n".to_owned(),
+        };
+        let query = protocol::format_mylist(&hash);
+        db.cache.put(&query, &reply).unwrap();
+        let entry = db.mylist_get(&hash).unwrap();
+        assert_eq!(entry.lid, 123456);
+        assert_eq!(entry.state, MylistState::Internal);
+    }
+}
+
+#[cfg(test)]
+mod test_empty_reply {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_empty_reply_is_retried() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            server.send_to(b"", src).unwrap();
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            server
+                .send_to(b"t1 200 abcd1 LOGIN ACCEPTED\n", src)
+                .unwrap();
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            server.send_to(b"t2 219 ENCODING CHANGED\n", src).unwrap();
+        });
+
+        let cache_dir = env::temp_dir().join("anidb-rs-test-empty-reply");
+        let mut db = Anidb::new(addr, &cache_dir).unwrap();
+        db.socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.login("foo", "bar").unwrap();
+        assert_eq!(db.connect().unwrap().session_id, "abcd1");
+    }
+}
+
+#[cfg(test)]
+mod test_tags {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_stray_reply_discarded() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let (len, src) = server.recv_from(&mut buf).unwrap();
+            assert!(std::str::from_utf8(&buf[..len])
+                .unwrap()
+                .ends_with("&tag=t1"));
+            // A late reply to some earlier command, then the real one.
+            server.send_to(b"t0 220 FILE\n1|2|3\n", src).unwrap();
+            server
+                .send_to(b"t1 200 abcd1 LOGIN ACCEPTED\n", src)
+                .unwrap();
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            server.send_to(b"t2 219 ENCODING CHANGED\n", src).unwrap();
+        });
+
+        let cache_dir = env::temp_dir().join("anidb-rs-test-stray-reply");
+        let mut db = Anidb::new(addr, &cache_dir).unwrap();
+        db.socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.login("foo", "bar").unwrap();
+        assert_eq!(db.connect().unwrap().session_id, "abcd1");
+    }
+}
+
+#[cfg(test)]
+mod test_bind {
+    use super::*;
+
+    #[test]
+    fn test_pick_address() {
+        let v4: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:9000".parse().unwrap();
+        assert_eq!(pick_address(&[v6, v4], None), Some(v4));
+        assert_eq!(pick_address(&[v6], None), Some(v6));
+        assert_eq!(pick_address(&[], None), None);
+
+        let bind6: SocketAddr = "[::]:0".parse().unwrap();
+        assert_eq!(pick_address(&[v4, v6], Some(bind6)), Some(v6));
+        assert_eq!(pick_address(&[v4], Some(bind6)), None);
+    }
+
+    #[test]
+    fn test_with_bind_address() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let bind = "127.0.0.1:0".parse().unwrap();
+        let db = Anidb::with_bind_address(server.local_addr().unwrap(), bind, MemoryCache::new())
+            .unwrap();
+        assert_eq!(db.address, server.local_addr().unwrap());
+        assert!(db.socket.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[test]
+    fn test_no_address() {
+        let none: &[SocketAddr] = &[];
+        match Anidb::with_cache(none, MemoryCache::new()) {
+            Err(AnidbError::StaticError(message)) => {
+                assert_eq!(message, "Could not resolve the server address")
+            }
+            Err(err) => panic!("Expected StaticError, got {:?}", err),
+            Ok(_) => panic!("Expected StaticError, got a client"),
+        }
     }
 }
 
 #[cfg(test)]
-mod test_parse {
+mod test_retry {
     use super::*;
+    use std::env;
+
+    /// Answer each request with the next reply, tagged like the request.
+    fn serve(server: UdpSocket, replies: Vec<&'static str>) {
+        let mut buf = [0; 2048];
+        for reply in replies {
+            let (len, src) = server.recv_from(&mut buf).unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).into_owned();
+            let tag = request.rsplit("tag=").next().unwrap().to_owned();
+            server
+                .send_to(format!("{} {}", tag, reply).as_bytes(), src)
+                .unwrap();
+        }
+    }
+
+    fn connect(addr: SocketAddr, name: &str) -> Anidb {
+        let mut db = Anidb::new(addr, &env::temp_dir().join(name)).unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.retry_delay = Duration::from_millis(10);
+        db.login("foo", "bar").unwrap();
+        db
+    }
+
+    #[test]
+    fn test_busy_is_retried() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "602 SERVER BUSY\n",
+                    "601 ANIDB OUT OF SERVICE - TRY AGAIN LATER\n",
+                    "208 UPTIME\n1000\n",
+                ],
+            )
+        });
+        let mut db = connect(addr, "anidb-rs-test-busy-retried");
+        assert_eq!(db.server_uptime().unwrap(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_retries_run_out() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "602 SERVER BUSY\n",
+                    "602 SERVER BUSY\n",
+                ],
+            )
+        });
+        let mut db = connect(addr, "anidb-rs-test-retries-run-out");
+        db.max_retries = 1;
+        match db.server_uptime() {
+            Err(AnidbError::ErrorCode(602, _)) => {}
+            other => panic!("Expected ErrorCode(602), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encoding_unanswered() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            for reply in &["200 abcd1 LOGIN ACCEPTED\n", "208 UPTIME\n1000\n"] {
+                let (request, src) = loop {
+                    let (len, src) = server.recv_from(&mut buf).unwrap();
+                    let request = String::from_utf8_lossy(&buf[..len]).into_owned();
+                    if !request.starts_with("ENCODING") {
+                        break (request, src);
+                    }
+                };
+                let tag = request.rsplit("tag=").next().unwrap().to_owned();
+                server
+                    .send_to(format!("{} {}", tag, reply).as_bytes(), src)
+                    .unwrap();
+            }
+        });
+        let mut db = Anidb::new(
+            addr,
+            &env::temp_dir().join("anidb-rs-test-encoding-unanswered"),
+        )
+        .unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.set_timeout(Duration::from_millis(100)).unwrap();
+        db.login("foo", "bar").unwrap();
+        assert_eq!(db.server_uptime().unwrap(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_timeout_not_cached() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "604 TIMEOUT - DELAY AND RESUBMIT\n",
+                    "230 ANIME\n1|1998-1998|TV Series|Cowboy Bebop|Cowboy Bebop|26|893116800\n",
+                ],
+            )
+        });
+        let mut db = connect(addr, "anidb-rs-test-timeout-not-cached");
+        db.cache.invalidate(&protocol::format_anime(1)).unwrap();
+        match db.anime_from_aid(1) {
+            Err(AnidbError::ErrorCode(604, _)) => {}
+            other => panic!("Expected ErrorCode(604), got {:?}", other),
+        }
+        // Asked again rather than answered with the cached timeout.
+        assert_eq!(db.anime_from_aid(1).unwrap().episode_count, 26);
+    }
+
+    #[test]
+    fn test_refresh_replaces_cached() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "230 ANIME\n1|1998-1998|TV Series|Cowboy Bebop|Cowboy Bebop|26|893116800\n",
+                ],
+            )
+        });
+        let mut db = connect(addr, "anidb-rs-test-refresh");
+        let query = protocol::format_anime(1);
+        let stale = ServerReply {
+            code: 230,
+            data: "ANIME\n1|1998-1998|TV Series|Cowboy Bebop|Cowboy Bebop|0|0\n".to_owned(),
+        };
+        db.cache.put(&query, &stale).unwrap();
+        assert_eq!(db.anime_from_aid(1).unwrap().episode_count, 0);
+
+        assert_eq!(db.refresh_anime(1).unwrap().episode_count, 26);
+        // The server has nothing left to say, so this must come from the cache.
+        assert_eq!(db.anime_from_aid(1).unwrap().episode_count, 26);
+    }
 
     #[test]
-    fn test_parse_reply_ok() {
-        let reply = b"500 LOGIN FAILED";
-        let ret = Anidb::parse_reply(reply, reply.len()).unwrap();
-        assert_eq!(ret.code, 500);
-        assert_eq!(ret.data, "LOGIN FAILED");
+    fn test_failed_refresh_keeps_cached() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "602 SERVER BUSY\n",
+                    "602 SERVER BUSY\n",
+                ],
+            )
+        });
+        let mut db = connect(addr, "anidb-rs-test-failed-refresh");
+        db.max_retries = 1;
+        let query = protocol::format_anime(1);
+        let stale = ServerReply {
+            code: 230,
+            data: "ANIME\n1|1998-1998|TV Series|Cowboy Bebop|Cowboy Bebop|0|0\n".to_owned(),
+        };
+        db.cache.put(&query, &stale).unwrap();
+
+        match db.refresh_anime(1) {
+            Err(AnidbError::ErrorCode(602, _)) => {}
+            other => panic!("Expected ErrorCode(602), got {:?}", other),
+        }
+        // The stale reply is still better than nothing.
+        assert_eq!(db.anime_from_aid(1).unwrap().episode_count, 0);
+    }
+
+    #[test]
+    fn test_stored_session_reused() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "208 UPTIME\n1000\n",
+                    // The second instance goes straight to UPTIME.
+                    "208 UPTIME\n2000\n",
+                ],
+            )
+        });
+        let cache_dir = env::temp_dir().join("anidb-rs-test-stored-session");
+        let bind = "127.0.0.1:0".parse().unwrap();
+        let mut first =
+            Anidb::with_bind_address(addr, bind, Cache::new(&cache_dir).unwrap()).unwrap();
+        first.cache.clear_session("foo").unwrap();
+        first.ratelimit = Duration::from_secs(0);
+        first.persist_session = true;
+        first.login("foo", "bar").unwrap();
+        assert_eq!(first.server_uptime().unwrap(), Duration::from_secs(1));
+        // The session only carries over to the same local address.
+        let local = first.socket.local_addr().unwrap();
+        drop(first);
+
+        let mut second =
+            Anidb::with_bind_address(addr, local, Cache::new(&cache_dir).unwrap()).unwrap();
+        second.ratelimit = Duration::from_secs(0);
+        second.persist_session = true;
+        second.login("foo", "bar").unwrap();
+        assert!(second.connect().unwrap().reused);
+        assert_eq!(second.server_uptime().unwrap(), Duration::from_secs(2));
     }
 
     #[test]
-    fn test_parse_reply_fail_1() {
-        let reply = b"a3i5LOGIN FAILED";
-        assert_eq!(true, Anidb::parse_reply(reply, reply.len()).is_err());
+    fn test_invalid_session_logs_in_again() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(
+                server,
+                vec![
+                    "200 abcd1 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "506 INVALID SESSION\n",
+                    "200 abcd2 LOGIN ACCEPTED\n",
+                    "219 ENCODING CHANGED\n",
+                    "208 UPTIME\n1000\n",
+                ],
+            )
+        });
+        let mut db = connect(addr, "anidb-rs-test-invalid-session");
+        assert_eq!(db.server_uptime().unwrap(), Duration::from_secs(1));
+        match db.session {
+            Session::Connected(ref session) => assert_eq!(session, "abcd2"),
+            ref other => panic!("Expected a new session, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_reply_size {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_oversized_reply_rejected() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            let (_, src) = server.recv_from(&mut buf).unwrap();
+            let mut reply = b"t1 200 abcd1 LOGIN ACCEPTED\n".to_vec();
+            reply.resize(200, b' ');
+            server.send_to(&reply, src).unwrap();
+        });
+
+        let cache_dir = env::temp_dir().join("anidb-rs-test-reply-size");
+        let mut db = Anidb::new(addr, &cache_dir).unwrap();
+        db.socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.max_reply_size = 100;
+        db.login("foo", "bar").unwrap();
+        match db.connect() {
+            Err(AnidbError::Error(ref message)) => assert!(message.contains("truncated")),
+            other => panic!("Expected a truncated reply, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_year {
+    use super::*;
+
+    fn with_year(year: &str) -> File {
+        File {
+            year: Some(year.to_owned()),
+            ..File::default()
+        }
     }
 
     #[test]
-    fn test_parse_reply_fail_2() {
-        let reply = b"34i5LOGIN FAILED";
-        assert_eq!(true, Anidb::parse_reply(reply, reply.len()).is_err());
+    fn test_year_range() {
+        assert_eq!(
+            with_year("2017-2017").year_range(),
+            Some((2017, Some(2017)))
+        );
+        assert_eq!(
+            with_year("2016-2019").year_range(),
+            Some((2016, Some(2019)))
+        );
+        assert_eq!(with_year("2015").year_range(), Some((2015, None)));
+        assert_eq!(with_year("2020-").year_range(), Some((2020, None)));
+        assert_eq!(File::default().year_range(), None);
+        assert_eq!(with_year("unknown").year_range(), None);
+        assert_eq!(with_year("2016-?").year_range(), None);
     }
 
     #[test]
-    fn test_parse_reply_too_short() {
-        let reply = b"3D";
-        assert_eq!(true, Anidb::parse_reply(reply, reply.len()).is_err());
+    fn test_sort_year() {
+        assert_eq!(with_year("2016-2019").sort_year(), Some(2016));
+        assert_eq!(File::default().sort_year(), None);
     }
+}
+
+#[cfg(test)]
+mod test_file_display {
+    use super::*;
 
     #[test]
-    fn test_parse_reply_exact_length() {
-        let reply = b"777 O";
-        let ret = Anidb::parse_reply(reply, reply.len()).unwrap();
-        assert_eq!(ret.code, 777);
-        assert_eq!(ret.data, "O");
+    fn test_file_display() {
+        let file = File {
+            series_romaji: Some("Little Witch Academia (2017)".to_owned()),
+            ep_number: Some("01".to_owned()),
+            ep_name: Some("A New Beginning".to_owned()),
+            group_short: Some("Asenshi".to_owned()),
+            ..File::default()
+        };
+        assert_eq!(
+            file.to_string(),
+            "Little Witch Academia (2017) - 01 - A New Beginning [Asenshi]"
+        );
     }
 
-    fn test_parse_file() {
-        let reply = b"220 FILE\n1879191|12235|183230|10435|Little Witch Academia (2017) - 01 - A New Beginning - [Asenshi](6a9d1e5c).mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)||???????????? (2017)'?? ?? ????? (2017)|lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi|1498599583";
+    #[test]
+    fn test_file_display_missing_fields() {
+        let file = File {
+            series_romaji: Some("Little Witch Academia (2017)".to_owned()),
+            ep_number: Some("01".to_owned()),
+            ..File::default()
+        };
+        assert_eq!(file.to_string(), "Little Witch Academia (2017) - 01");
+        let file = File {
+            fid: 1879191,
+            ..File::default()
+        };
+        assert_eq!(file.to_string(), "fid 1879191");
     }
 }
 
 #[cfg(test)]
-mod test_format {
+mod test_mylist_state {
     use super::*;
 
     #[test]
-    fn test_format_login_string() {
-        let login_string = Anidb::format_login_string("leeloo_dallas", "multipass");
+    fn test_mylist_state_from_str() {
+        assert_eq!("hdd".parse::<MylistState>().unwrap(), MylistState::Internal);
         assert_eq!(
-            login_string,
-            "AUTH user=leeloo_dallas&pass=multipass&protover=3&client=anidbrs&clientver=1"
+            "remote".parse::<MylistState>().unwrap(),
+            MylistState::Remote
         );
+        assert!("nas".parse::<MylistState>().is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_redaction {
+    use super::*;
+
+    #[test]
+    fn test_session_debug_redacted() {
+        let pending = Session::Pending {
+            user: "leeloo_dallas".to_owned(),
+            pwd: "multipass".to_owned(),
+        };
+        let debug = format!("{:?}", pending);
+        assert!(debug.contains("leeloo_dallas"));
+        assert!(!debug.contains("multipass"));
+
+        let connected = Session::Connected("abcd1".to_owned());
+        assert!(!format!("{:?}", connected).contains("abcd1"));
+    }
+}
+
+#[cfg(test)]
+mod test_ratelimit {
+    use super::*;
+
+    #[test]
+    fn test_timeout() {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let cache_dir = std::env::temp_dir().join("anidb-rs-test-timeout");
+        let mut db = Anidb::new(silent.local_addr().unwrap(), &cache_dir).unwrap();
+        db.ratelimit = Duration::from_secs(0);
+        db.set_timeout(Duration::from_millis(50)).unwrap();
+        match db.ping() {
+            Err(AnidbError::Timeout) => {}
+            other => panic!("Expected Timeout, got {:?}", other),
+        }
+        // The ping was sent again once, before giving up.
+        let mut buf = [0; 64];
+        silent.set_nonblocking(true).unwrap();
+        assert!(silent.recv_from(&mut buf).is_ok());
+        assert!(silent.recv_from(&mut buf).is_ok());
+        assert!(silent.recv_from(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_is_rate_limited() {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let cache_dir = std::env::temp_dir().join("anidb-rs-test-is-rate-limited");
+        let mut db = Anidb::new(silent.local_addr().unwrap(), &cache_dir).unwrap();
+        assert!(db.is_rate_limited());
+        db.ratelimit = Duration::from_secs(0);
+        assert!(!db.is_rate_limited());
     }
 
     #[test]
-    fn test_format_logout_string() {
-        let logout_str = Anidb::format_logout_string("abcd1234");
-        assert_eq!(logout_str, "LOGOUT s=abcd1234");
+    fn test_banned_refuses_to_send() {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let cache_dir = std::env::temp_dir().join("anidb-rs-test-banned");
+        let mut db = Anidb::new(silent.local_addr().unwrap(), &cache_dir).unwrap();
+        db.banned_until = Some(Instant::now() + Duration::from_secs(60 * 60));
+        db.ban_reason = "Flooding".to_owned();
+        // The silent socket would never answer, so this only returns if nothing is sent.
+        match db.send_wait_reply("PING") {
+            Err(AnidbError::Banned(ref reason)) => assert_eq!(reason, "Flooding"),
+            other => panic!("Expected Banned, got {:?}", other),
+        }
+        assert!(db.banned_until.is_some());
+    }
+
+    #[test]
+    fn test_adaptive_ratelimit() {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let cache_dir = std::env::temp_dir().join("anidb-rs-test-adaptive-ratelimit");
+        let mut db = Anidb::new(silent.local_addr().unwrap(), &cache_dir).unwrap();
+        db.adapt_interval(602);
+        assert_eq!(db.send_interval(), Duration::from_secs(4));
+
+        db.adaptive_ratelimit = true;
+        assert_eq!(db.send_interval(), ADAPTIVE_MIN_INTERVAL);
+        db.adapt_interval(602);
+        assert_eq!(db.send_interval(), Duration::from_secs(4));
+        db.adapt_interval(220);
+        assert_eq!(db.send_interval(), Duration::from_secs(4));
+
+        // Idle long enough to earn back two fast messages.
+        db.spend_burst(Duration::from_secs(8));
+        assert_eq!(db.send_interval(), ADAPTIVE_MIN_INTERVAL);
+        db.spend_burst(ADAPTIVE_MIN_INTERVAL);
+        assert_eq!(db.send_interval(), ADAPTIVE_MIN_INTERVAL);
+        db.spend_burst(ADAPTIVE_MIN_INTERVAL);
+        assert_eq!(db.send_interval(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_adaptive_ratelimit_sustained() {
+        let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let cache_dir = std::env::temp_dir().join("anidb-rs-test-adaptive-sustained");
+        let mut db = Anidb::new(silent.local_addr().unwrap(), &cache_dir).unwrap();
+        db.adaptive_ratelimit = true;
+        // Every message is sent as soon as allowed, and AniDB never complains.
+        for sent in 0..1000 {
+            let interval = db.send_interval();
+            if sent >= ADAPTIVE_BURST {
+                assert_eq!(interval, db.ratelimit);
+            }
+            db.spend_burst(interval);
+            db.adapt_interval(220);
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let delay = Duration::from_secs(30);
+        assert_eq!(Anidb::retry_backoff(delay, 0), delay);
+        assert_eq!(Anidb::retry_backoff(delay, 2), Duration::from_secs(120));
+        assert_eq!(Anidb::retry_backoff(delay, 10), MAX_RETRY_DELAY);
+        assert_eq!(Anidb::retry_backoff(delay, 40), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_send_delay_no_jitter() {
+        let ratelimit = Duration::from_secs(4);
+        let delay = Anidb::send_delay(ratelimit, Duration::from_secs(0));
+        assert_eq!(delay, ratelimit);
+    }
+
+    #[test]
+    fn test_send_delay_jitter_bounds() {
+        let ratelimit = Duration::from_secs(4);
+        let jitter = Duration::from_millis(300);
+        for _ in 0..100 {
+            let delay = Anidb::send_delay(ratelimit, jitter);
+            assert!(delay >= ratelimit);
+            assert!(delay <= ratelimit + jitter);
+        }
     }
 }
@@ -124,6 +124,12 @@ impl Md4 {
     }
 }
 
+impl Default for Md4 {
+    fn default() -> Md4 {
+        Md4::new()
+    }
+}
+
 impl Digest for Md4 {
     fn input(&mut self, input: &[u8]) {
         assert!(!self.finished);
@@ -168,3 +174,61 @@ impl Digest for Md4 {
         64
     }
 }
+
+/// The MD4 digest of `data`, in one call.
+pub fn md4_digest(data: &[u8]) -> [u8; 16] {
+    let mut digest = [0; 16];
+    let mut ctx = Md4::new();
+    ctx.input(data);
+    ctx.result(&mut digest);
+    digest
+}
+
+/// The MD4 digest of `data` as lowercase hex, the way RFC 1320 writes it.
+pub fn md4_hex(data: &[u8]) -> String {
+    md4_digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test_md4 {
+    use super::*;
+
+    // The test suite from RFC 1320, appendix A.5.
+    #[test]
+    fn test_rfc1320_vectors() {
+        let vectors: &[(&str, &str)] = &[
+            ("", "31d6cfe0d16ae931b73c59d7e0c089c0"),
+            ("a", "bde52cb31de33e46245e05fbdbd6fb24"),
+            ("abc", "a448017aaf21d8525fc10ae87aa6729d"),
+            ("message digest", "d9130a8164549fe818874806e1c7014b"),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "d79e1c308aa5bbcdeea8ed63df412da9",
+            ),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "043f8582f241db351ce627e153e7f0e4",
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "e33b4ddc9c38f2199c3e7b164fcc0536",
+            ),
+        ];
+        for &(input, expected) in vectors {
+            assert_eq!(md4_hex(input.as_bytes()), expected, "MD4({:?})", input);
+        }
+    }
+
+    #[test]
+    fn test_digest_matches_incremental() {
+        let mut ctx = Md4::new();
+        ctx.input(b"message ");
+        ctx.input(b"digest");
+        let mut digest = [0; 16];
+        ctx.result(&mut digest);
+        assert_eq!(md4_digest(b"message digest"), digest);
+    }
+}
@@ -0,0 +1,1666 @@
+//! Formatting of AniDB UDP API commands, and parsing of the replies.
+//!
+//! Everything here is pure; sending and receiving is up to `Anidb`, or whoever else.
+//!
+//! The reply parsers all map codes the same way:
+//!
+//! - 2xx codes are answers, and become `Ok`.
+//! - 3xx codes that still answer the question, like `310 FILE ALREADY IN MYLIST`, also
+//!   become `Ok`, with a type that says which case it was, e.g. `MylistOutcome`.
+//! - 3xx codes saying the thing doesn't exist, like `320 NO SUCH FILE` or `330 NO SUCH
+//!   ANIME`, become `AnidbError::NoSuchFile`.
+//! - `322 MULTIPLE FILES FOUND` becomes `AnidbError::MultipleFiles`, since there's no single
+//!   answer; the caller has to pick one.
+//! - `555 BANNED` becomes `AnidbError::Banned`, whatever the command was; see `parse_ban`.
+//! - Anything else becomes `AnidbError::ErrorCode`, with the reply text.
+
+use ed2k::Ed2kHash;
+use encoding_rs::WINDOWS_1252;
+use errors::{AnidbError, Result};
+use escape;
+use flate2::read::ZlibDecoder;
+use std::io::Read;
+use std::str;
+use std::str::FromStr;
+use std::time::Duration;
+use {
+    Anime, Episode, File, Group, MylistEntry, MylistOutcome, MylistState, Notification, NotifyRef,
+    ServerReply, VoteType,
+};
+
+/// Define `ReplyCode`, with a variant for each code, and the conversions to and from numbers.
+macro_rules! reply_codes {
+    ($($name:ident = $code:literal,)*) => {
+        /// The reply codes this crate knows about, named as in the AniDB UDP API docs.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum ReplyCode {
+            $($name,)*
+            /// Any code not listed here.
+            Unknown(i32),
+        }
+
+        impl ReplyCode {
+            pub fn from_i32(code: i32) -> ReplyCode {
+                match code {
+                    $($code => ReplyCode::$name,)*
+                    code => ReplyCode::Unknown(code),
+                }
+            }
+
+            pub fn to_i32(self) -> i32 {
+                match self {
+                    $(ReplyCode::$name => $code,)*
+                    ReplyCode::Unknown(code) => code,
+                }
+            }
+        }
+    };
+}
+
+reply_codes! {
+    LoginAccepted = 200,
+    LoginAcceptedNewVersion = 201,
+    Uptime = 208,
+    EncryptionEnabled = 209,
+    MylistEntryAdded = 210,
+    EncodingChanged = 219,
+    File = 220,
+    Mylist = 221,
+    Anime = 230,
+    Episode = 240,
+    Group = 250,
+    Voted = 260,
+    VoteFound = 261,
+    VoteUpdated = 262,
+    NotifyList = 291,
+    NotifyGetMessage = 292,
+    NotifyGetNotify = 293,
+    Pong = 300,
+    FileAlreadyInMylist = 310,
+    MultipleMylistEntries = 312,
+    NoSuchFile = 320,
+    NoSuchEntry = 321,
+    MultipleFilesFound = 322,
+    NoSuchAnime = 330,
+    NoSuchEpisode = 340,
+    NoSuchGroup = 350,
+    NoSuchVote = 360,
+    NoSuchNotify = 392,
+    LoginFailed = 500,
+    LoginFirst = 501,
+    AccessDenied = 502,
+    ClientVersionOutdated = 503,
+    ClientBanned = 504,
+    IllegalInput = 505,
+    InvalidSession = 506,
+    EncodingNotSupported = 519,
+    Banned = 555,
+    UnknownCommand = 598,
+    InternalServerError = 600,
+    OutOfService = 601,
+    ServerBusy = 602,
+    Timeout = 604,
+    Version = 998,
+}
+
+/// The AUTH command, for logging in.
+pub fn format_login(username: &str, password: &str, client: &str, client_version: u32) -> String {
+    format!(
+        "AUTH user={}&pass={}&protover=3&client={}&clientver={}",
+        username, password, client, client_version
+    )
+}
+
+/// The LOGOUT command, ending the session.
+pub fn format_logout(session_id: &str) -> String {
+    format!("LOGOUT s={}", session_id)
+}
+
+/// The ENCODING command, choosing the character set of the session's replies.
+pub fn format_encoding(name: &str) -> String {
+    format!("ENCODING name={}", name)
+}
+
+/// The PING command, which needs no session.
+pub fn format_ping() -> String {
+    "PING".to_owned()
+}
+
+/// The VERSION command, which needs no session.
+pub fn format_version() -> String {
+    "VERSION".to_owned()
+}
+
+/// The UPTIME command. Unlike VERSION, it needs a session.
+pub fn format_uptime() -> String {
+    "UPTIME".to_owned()
+}
+
+/// The ENCRYPT command, asking for the session to be encrypted with the user's API key.
+/// It has to be sent before AUTH.
+pub fn format_encrypt(username: &str) -> String {
+    format!("ENCRYPT user={}&type=1", username)
+}
+
+/// Which fields to ask for in a FILE command. Fields that aren't asked for are left empty,
+/// or zero, in the `File`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMask {
+    pub aid: bool,
+    pub eid: bool,
+    pub gid: bool,
+    pub other_episodes: bool,
+    pub state: bool,
+    /// Echo the file size back. See `Anidb::verify_echo`.
+    pub size: bool,
+    /// Echo the ed2k hash back. See `Anidb::verify_echo`.
+    pub ed2k: bool,
+    pub filename: bool,
+    pub total_eps: bool,
+    pub highest_ep: bool,
+    pub year: bool,
+    pub typ: bool,
+    pub series_romaji: bool,
+    pub series_english: bool,
+    pub series_other: bool,
+    pub series_short: bool,
+    pub ep_number: bool,
+    pub ep_name: bool,
+    pub ep_romaji: bool,
+    pub group_name: bool,
+    pub group_short: bool,
+}
+
+/// Everything but the echoed size and hash.
+impl Default for FileMask {
+    fn default() -> FileMask {
+        FileMask {
+            aid: true,
+            eid: true,
+            gid: true,
+            other_episodes: true,
+            state: true,
+            size: false,
+            ed2k: false,
+            filename: true,
+            total_eps: true,
+            highest_ep: true,
+            year: true,
+            typ: true,
+            series_romaji: true,
+            series_english: true,
+            series_other: true,
+            series_short: true,
+            ep_number: true,
+            ep_name: true,
+            ep_romaji: true,
+            group_name: true,
+            group_short: true,
+        }
+    }
+}
+
+impl FileMask {
+    /// Nothing but the fid, which is always sent. A starting point for asking for only a
+    /// few fields.
+    pub fn none() -> FileMask {
+        FileMask {
+            aid: false,
+            eid: false,
+            gid: false,
+            other_episodes: false,
+            state: false,
+            size: false,
+            ed2k: false,
+            filename: false,
+            total_eps: false,
+            highest_ep: false,
+            year: false,
+            typ: false,
+            series_romaji: false,
+            series_english: false,
+            series_other: false,
+            series_short: false,
+            ep_number: false,
+            ep_name: false,
+            ep_romaji: false,
+            group_name: false,
+            group_short: false,
+        }
+    }
+
+    /// The fmask, as hex. Bits are numbered from the most significant bit of the first byte.
+    pub fn fmask(&self) -> String {
+        Self::hex(
+            5,
+            &[
+                (1, self.aid),
+                (2, self.eid),
+                (3, self.gid),
+                (5, self.other_episodes),
+                (7, self.state),
+                (8, self.size),
+                (9, self.ed2k),
+                (31, self.filename),
+            ],
+        )
+    }
+
+    /// The amask, as hex.
+    pub fn amask(&self) -> String {
+        Self::hex(
+            4,
+            &[
+                (0, self.total_eps),
+                (1, self.highest_ep),
+                (2, self.year),
+                (3, self.typ),
+                (8, self.series_romaji),
+                (10, self.series_english),
+                (11, self.series_other),
+                (12, self.series_short),
+                (16, self.ep_number),
+                (17, self.ep_name),
+                (18, self.ep_romaji),
+                (24, self.group_name),
+                (25, self.group_short),
+            ],
+        )
+    }
+
+    fn hex(bytes: usize, bits: &[(usize, bool)]) -> String {
+        let mut mask = vec![0u8; bytes];
+        for &(bit, set) in bits {
+            if set {
+                mask[bit / 8] |= 0x80 >> (bit % 8);
+            }
+        }
+        mask.iter().map(|byte| format!("{:02X}", byte)).collect()
+    }
+}
+
+/// The FILE command, looking a file up by hash.
+pub fn format_file(hash: &Ed2kHash, mask: &FileMask) -> String {
+    format!(
+        "FILE size={}&ed2k={}&fmask={}&amask={}",
+        hash.size,
+        hash.hex,
+        mask.fmask(),
+        mask.amask()
+    )
+}
+
+/// The FILE command, looking a file up by file id.
+pub fn format_file_fid(fid: u32, mask: &FileMask) -> String {
+    format!(
+        "FILE fid={}&fmask={}&amask={}",
+        fid,
+        mask.fmask(),
+        mask.amask()
+    )
+}
+
+/// The FILE command, looking a file up by the anime, release group and episode number it's
+/// for. The episode number is as AniDB writes it, such as "5" or "S1".
+pub fn format_file_episode(aid: u32, gid: u32, epno: &str, mask: &FileMask) -> String {
+    format!(
+        "FILE aid={}&gid={}&epno={}&fmask={}&amask={}",
+        aid,
+        gid,
+        epno,
+        mask.fmask(),
+        mask.amask()
+    )
+}
+
+/// The ANIME command, looking an anime up by anime id.
+pub fn format_anime(aid: u32) -> String {
+    // aid, year, type, romaji name, english name, episodes and air date.
+    format!("ANIME aid={}&amask=B0A09000000000", aid)
+}
+
+/// The EPISODE command, looking an episode up by episode id.
+pub fn format_episode(eid: u32) -> String {
+    format!("EPISODE eid={}", eid)
+}
+
+/// The GROUP command, looking a release group up by group id.
+pub fn format_group(gid: u32) -> String {
+    format!("GROUP gid={}", gid)
+}
+
+/// The MYLIST command, looking a mylist entry up by the file's hash.
+pub fn format_mylist(hash: &Ed2kHash) -> String {
+    format!("MYLIST size={}&ed2k={}", hash.size, hash.hex)
+}
+
+/// The MYLIST command, looking a mylist entry up by its mylist id.
+pub fn format_mylist_lid(lid: u32) -> String {
+    format!("MYLIST lid={}", lid)
+}
+
+/// The MYLISTADD command, adding a file to mylist.
+pub fn format_mylist_add(hash: &Ed2kHash, state: MylistState, viewed: bool) -> String {
+    format!(
+        "MYLISTADD size={}&ed2k={}&state={}&viewed={}",
+        hash.size, hash.hex, state as u8, viewed as u8
+    )
+}
+
+/// The VOTE command, rating something. `value` is the rating times 100. An episode vote
+/// is an anime vote with the episode number added.
+pub fn format_vote(typ: VoteType, id: u32, value: u16) -> String {
+    match typ {
+        VoteType::Anime => format!("VOTE type=1&id={}&value={}", id, value),
+        VoteType::Group => format!("VOTE type=3&id={}&value={}", id, value),
+        VoteType::Episode { epno } => {
+            format!("VOTE type=1&id={}&value={}&epno={}", id, value, epno)
+        }
+    }
+}
+
+/// The NOTIFYLIST command, listing pending notifications and messages.
+pub fn format_notify_list() -> String {
+    "NOTIFYLIST".to_owned()
+}
+
+/// The NOTIFYGET command, fetching one notification or message.
+pub fn format_notify_get(typ: char, id: u32) -> String {
+    format!("NOTIFYGET type={}&id={}", typ, id)
+}
+
+/// Add a `name=value` parameter to a command, whether or not it has any parameters yet.
+pub fn add_param(message: &str, name: &str, value: &str) -> String {
+    let separator = if message.contains(' ') { '&' } else { ' ' };
+    format!("{}{}{}={}", message, separator, name, value)
+}
+
+/// Parse the reply from the server which is expected to be in xxx - format. If that is not the
+/// case this function will return an error that the reply couldn't be parsed.
+///
+/// Compressed replies, marked by two leading zero bytes, are inflated first. Text that isn't
+/// UTF-8 is decoded as Windows-1252; see `decode_text`.
+pub fn parse_reply(reply: &[u8], len: usize) -> Result<ServerReply> {
+    parse_tagged_reply(reply, len, None, false)
+}
+
+/// Like `parse_reply`, but for a command sent with a tag, which AniDB echoes before the
+/// reply code. The tag is stripped, and a different one gives `AnidbError::TagMismatch`.
+///
+/// Untagged 5xx and 6xx replies are accepted, since AniDB may reject a command before it
+/// gets as far as reading the tag.
+///
+/// With `strict`, a reply that isn't valid UTF-8 is an error.
+pub fn parse_tagged_reply(
+    reply: &[u8],
+    len: usize,
+    tag: Option<&str>,
+    strict: bool,
+) -> Result<ServerReply> {
+    let inflated;
+    let (reply, len) = if len > 2 && reply[..2] == [0, 0] {
+        let mut buf = Vec::new();
+        ZlibDecoder::new(&reply[2..len]).read_to_end(&mut buf)?;
+        inflated = buf;
+        (&inflated[..], inflated.len())
+    } else {
+        (reply, len)
+    };
+    let (reply, len) = match tag {
+        Some(tag) if len > 0 => {
+            let prefix = format!("{} ", tag);
+            if reply[..len].starts_with(prefix.as_bytes()) {
+                (&reply[prefix.len()..], len - prefix.len())
+            } else if reply[0] == b'5' || reply[0] == b'6' {
+                (reply, len)
+            } else {
+                return Err(AnidbError::TagMismatch {
+                    expected: tag.to_owned(),
+                    reply: String::from_utf8_lossy(&reply[..len]).into_owned(),
+                });
+            }
+        }
+        _ => (reply, len),
+    };
+    if len == 0 {
+        return Err(AnidbError::EmptyReply);
+    }
+    if len < 5 {
+        return Err(AnidbError::ShortReply(reply[..len].to_vec()));
+    }
+    let code_str = str::from_utf8(&reply[0..3])?;
+    let code = code_str.parse::<i32>()?;
+    Ok(ServerReply {
+        code: code,
+        data: decode_text(&reply[4..len], strict)?,
+    })
+}
+
+/// Decode reply text. AniDB sends UTF-8 once asked to with ENCODING, but a session that
+/// didn't ask, or couldn't, gets its older encoding, which is Windows-1252 for the Latin
+/// scripts that can be sent at all. Unless `strict`, that's the fallback; it can decode any
+/// bytes, so nothing is ever replaced with U+FFFD.
+pub fn decode_text(text: &[u8], strict: bool) -> Result<String> {
+    match str::from_utf8(text) {
+        Ok(text) => Ok(text.to_owned()),
+        Err(err) if strict => Err(AnidbError::Utf8Error(err)),
+        Err(_) => {
+            debug!("Reply isn't UTF-8; decoding it as Windows-1252");
+            Ok(WINDOWS_1252
+                .decode_without_bom_handling(text)
+                .0
+                .into_owned())
+        }
+    }
+}
+
+/// How long a ban lasts, when the 555 reply doesn't say.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+
+/// If the reply is `555 BANNED`, return the reason given, and how long the ban lasts. The
+/// duration is read from the reason, e.g. "banned for 2 hours", if it has one.
+pub fn parse_ban(reply: &ServerReply) -> Option<(String, Duration)> {
+    if reply.reply_code() != ReplyCode::Banned {
+        return None;
+    }
+    let mut lines = reply.data.lines();
+    lines.next();
+    let reason = lines.collect::<Vec<_>>().join(" ");
+    let duration = parse_ban_duration(&reason).unwrap_or(DEFAULT_BAN_DURATION);
+    Some((reason, duration))
+}
+
+/// Find the first "<number> <unit>" in the text.
+fn parse_ban_duration(text: &str) -> Option<Duration> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    for pair in words.windows(2) {
+        let count = match pair[0].parse::<u64>() {
+            Ok(count) => count,
+            Err(_) => continue,
+        };
+        let unit = pair[1].to_lowercase();
+        let secs = if unit.starts_with("sec") {
+            1
+        } else if unit.starts_with("min") {
+            60
+        } else if unit.starts_with("hour") {
+            60 * 60
+        } else if unit.starts_with("day") {
+            24 * 60 * 60
+        } else {
+            continue;
+        };
+        return Some(Duration::from_secs(count * secs));
+    }
+    None
+}
+
+/// Validates that the auth command has a correct reply from the server
+///
+/// Code 201 means the login was accepted, but there's a newer version of the client.
+pub fn validate_auth(reply: &ServerReply) -> Result<String> {
+    let expected = match reply.reply_code() {
+        ReplyCode::LoginAccepted => "LOGIN ACCEPTED\n",
+        ReplyCode::LoginAcceptedNewVersion => "LOGIN ACCEPTED - NEW VERSION AVAILABLE\n",
+        ReplyCode::ClientVersionOutdated => return Err(AnidbError::ClientOutdated),
+        ReplyCode::ClientBanned => {
+            // "CLIENT BANNED - reason"
+            let first_line = reply.data.lines().next().unwrap_or("");
+            let reason = match first_line.find(" - ") {
+                Some(dash) => &first_line[dash + " - ".len()..],
+                None => first_line,
+            };
+            return Err(AnidbError::ClientBanned(reason.to_owned()));
+        }
+        _ => return Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    };
+
+    let v: Vec<&str> = reply.data.splitn(2, ' ').collect();
+
+    if v.len() != 2 {
+        return Err(AnidbError::Error(format!(
+            "Invalid AUTH reply: {} expected session key",
+            reply.data
+        )));
+    }
+
+    if v[1] != expected {
+        return Err(AnidbError::Error(format!(
+            "Invalid AUTH reply: {} {:?} expected",
+            reply.data, expected
+        )));
+    }
+
+    Ok(v[0].to_owned())
+}
+
+/// Parse the reply to ENCODING. `519 ENCODING NOT SUPPORTED` comes back as `ErrorCode`.
+pub fn parse_encoding(reply: &ServerReply) -> Result<()> {
+    match reply.reply_code() {
+        ReplyCode::EncodingChanged => Ok(()),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse the reply to PING, which should be `300 PONG`.
+pub fn parse_ping(reply: &ServerReply) -> Result<()> {
+    match reply.reply_code() {
+        ReplyCode::Pong => Ok(()),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse the reply to VERSION, returning the server's version.
+pub fn parse_version(reply: &ServerReply) -> Result<String> {
+    match reply.reply_code() {
+        ReplyCode::Version => Ok(reply.fields().first().unwrap_or(&"").to_string()),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse the reply to UPTIME, returning how long the server has been up.
+pub fn parse_uptime(reply: &ServerReply) -> Result<Duration> {
+    match reply.reply_code() {
+        ReplyCode::Uptime => {
+            let millis: u64 = parse_field("uptime", reply.fields().first().unwrap_or(&""))?;
+            Ok(Duration::from_millis(millis))
+        }
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse the reply to ENCRYPT, returning the salt for `encryption::derive_key`.
+///
+/// 309 means the user hasn't set an API key on AniDB, and 394 that the user doesn't exist;
+/// both come back as `ErrorCode`.
+pub fn parse_encrypt(reply: &ServerReply) -> Result<String> {
+    if reply.reply_code() != ReplyCode::EncryptionEnabled {
+        return Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned()));
+    }
+    match reply.data.split(' ').next() {
+        Some(salt) if !salt.is_empty() && reply.data.contains("ENCRYPTION ENABLED") => {
+            Ok(salt.to_owned())
+        }
+        _ => Err(AnidbError::ParseField {
+            field: "salt",
+            value: reply.data.to_owned(),
+        }),
+    }
+}
+
+/// Parse a FILE reply to a query with the given mask. If `echo` is set, the reply must
+/// contain the echoed size and ed2k hash, and they must match it.
+pub fn parse_file(reply: &ServerReply, mask: &FileMask, echo: Option<&Ed2kHash>) -> Result<File> {
+    match reply.reply_code() {
+        ReplyCode::MultipleFilesFound => {
+            let fids = reply
+                .fields()
+                .iter()
+                .map(|fid| fid.parse())
+                .collect::<std::result::Result<Vec<u32>, _>>()?;
+            Err(AnidbError::MultipleFiles(fids))
+        }
+        ReplyCode::NoSuchFile => Err(AnidbError::NoSuchFile),
+        ReplyCode::File => {
+            let fields = reply.fields();
+            let mut fields = fields.into_iter();
+            // The fields come in mask bit order, with only the ones we asked for present.
+            let mut next = |wanted: bool, name: &'static str| {
+                if !wanted {
+                    return Ok(None);
+                }
+                match fields.next() {
+                    Some(field) => Ok(Some(field)),
+                    None => Err(AnidbError::ParseField {
+                        field: name,
+                        value: String::new(),
+                    }),
+                }
+            };
+            let mut file = File {
+                fid: parse_field("fid", next(true, "fid")?.unwrap())?,
+                ..File::default()
+            };
+            if let Some(aid) = next(mask.aid, "aid")? {
+                file.aid = parse_field("aid", aid)?;
+            }
+            if let Some(eid) = next(mask.eid, "eid")? {
+                file.eid = parse_field("eid", eid)?;
+            }
+            if let Some(gid) = next(mask.gid, "gid")? {
+                file.gid = parse_field("gid", gid)?;
+            }
+            if let Some(other_episodes) = next(mask.other_episodes, "other_episodes")? {
+                file.other_episodes =
+                    parse_other_episodes(other_episodes).map_err(|_| AnidbError::ParseField {
+                        field: "other_episodes",
+                        value: other_episodes.to_owned(),
+                    })?;
+            }
+            if let Some(state) = next(mask.state, "state")? {
+                file.state = parse_field("state", state)?;
+            }
+            let size = next(mask.size, "size")?;
+            let ed2k = next(mask.ed2k, "ed2k")?;
+            if let Some(hash) = echo {
+                let size: u64 = parse_field("size", size.unwrap_or(""))?;
+                let ed2k = ed2k.unwrap_or("");
+                if size != hash.size || !ed2k.eq_ignore_ascii_case(&hash.hex) {
+                    return Err(AnidbError::Error(format!(
+                        "FILE reply is for size={}&ed2k={}, expected size={}&ed2k={}",
+                        size, ed2k, hash.size, hash.hex
+                    )));
+                }
+            }
+            file.filename = known(next(mask.filename, "filename")?).map(escape::percent_decode);
+            // An episode count of zero means it isn't known yet, e.g. for airing series.
+            file.total_eps = match known(next(mask.total_eps, "total_eps")?) {
+                Some(total_eps) => Some(parse_field("total_eps", total_eps)?).filter(|&n| n > 0),
+                None => None,
+            };
+            file.highest_ep = match known(next(mask.highest_ep, "highest_ep")?) {
+                Some(highest_ep) => Some(parse_field("highest_ep", highest_ep)?),
+                None => None,
+            };
+            let mut text = |wanted: bool, name: &'static str| next(wanted, name).map(known);
+            let unescape = escape::unescape_field;
+            file.year = text(mask.year, "year")?.map(unescape);
+            file.typ = text(mask.typ, "typ")?.map(unescape);
+            file.series_romaji = text(mask.series_romaji, "series_romaji")?.map(unescape);
+            file.series_english = text(mask.series_english, "series_english")?.map(unescape);
+            // Split before unescaping, since escaped apostrophes would look like separators.
+            file.series_other =
+                split_titles(text(mask.series_other, "series_other")?.unwrap_or(""));
+            file.series_short = text(mask.series_short, "series_short")?.map(unescape);
+            file.ep_number = text(mask.ep_number, "ep_number")?.map(unescape);
+            file.ep_name = text(mask.ep_name, "ep_name")?.map(unescape);
+            file.ep_romaji = text(mask.ep_romaji, "ep_romaji")?.map(unescape);
+            file.group_name = text(mask.group_name, "group_name")?.map(unescape);
+            file.group_short = text(mask.group_short, "group_short")?.map(unescape);
+            Ok(file)
+        }
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// AniDB sends an empty field for anything it doesn't know.
+fn known(field: Option<&str>) -> Option<&str> {
+    field.filter(|field| !field.is_empty())
+}
+
+/// Parse a single field, naming it in the error if that fails.
+fn parse_field<T: FromStr>(field: &'static str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| AnidbError::ParseField {
+        field,
+        value: value.to_owned(),
+    })
+}
+
+/// Parse a list of `eid,percentage` pairs, separated by `'`.
+fn parse_other_episodes(episodes: &str) -> Result<Vec<(u32, u8)>> {
+    let mut parsed = Vec::new();
+    for episode in episodes.split('\'').filter(|episode| !episode.is_empty()) {
+        let mut parts = episode.splitn(2, ',');
+        let eid = parts.next().unwrap_or("").parse()?;
+        let percentage = parts.next().unwrap_or("").parse()?;
+        parsed.push((eid, percentage));
+    }
+    Ok(parsed)
+}
+
+/// Split a list of titles, which AniDB separates with `'`.
+fn split_titles(titles: &str) -> Vec<String> {
+    titles
+        .split('\'')
+        .filter(|title| !title.is_empty())
+        .map(escape::unescape_field)
+        .collect()
+}
+
+/// Parse an ANIME reply.
+pub fn parse_anime(reply: &ServerReply) -> Result<Anime> {
+    match reply.reply_code() {
+        ReplyCode::Anime => {
+            let fields = reply.fields();
+            if fields.len() < 7 {
+                return Err(AnidbError::Error(format!(
+                    "ANIME reply has {} fields, expected 7",
+                    fields.len()
+                )));
+            }
+            let unescape = escape::unescape_field;
+            Ok(Anime {
+                aid: parse_field("aid", fields[0])?,
+                year: unescape(fields[1]),
+                typ: unescape(fields[2]),
+                romaji_name: unescape(fields[3]),
+                english_name: unescape(fields[4]),
+                episode_count: parse_field("episode_count", fields[5])?,
+                air_date: parse_field("air_date", fields[6])?,
+            })
+        }
+        ReplyCode::NoSuchAnime => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse an EPISODE reply.
+pub fn parse_episode(reply: &ServerReply) -> Result<Episode> {
+    match reply.reply_code() {
+        ReplyCode::Episode => {
+            let fields = reply.fields();
+            if fields.len() < 10 {
+                return Err(AnidbError::Error(format!(
+                    "EPISODE reply has {} fields, expected at least 10",
+                    fields.len()
+                )));
+            }
+            let unescape = escape::unescape_field;
+            Ok(Episode {
+                eid: parse_field("eid", fields[0])?,
+                aid: parse_field("aid", fields[1])?,
+                length: parse_field("length", fields[2])?,
+                rating: parse_field("rating", fields[3])?,
+                votes: parse_field("votes", fields[4])?,
+                ep_number: unescape(fields[5]),
+                name_english: unescape(fields[6]),
+                name_romaji: unescape(fields[7]),
+                name_kanji: unescape(fields[8]),
+                aired: parse_field("aired", fields[9])?,
+            })
+        }
+        ReplyCode::NoSuchEpisode => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse a GROUP reply.
+pub fn parse_group(reply: &ServerReply) -> Result<Group> {
+    match reply.reply_code() {
+        ReplyCode::Group => {
+            let fields = reply.fields();
+            if fields.len() < 10 {
+                return Err(AnidbError::Error(format!(
+                    "GROUP reply has {} fields, expected at least 10",
+                    fields.len()
+                )));
+            }
+            // Rating, votes and anime and file counts come between the gid and the names.
+            let unescape = escape::unescape_field;
+            Ok(Group {
+                gid: parse_field("gid", fields[0])?,
+                name: unescape(fields[5]),
+                short_name: unescape(fields[6]),
+                irc_channel: unescape(fields[7]),
+                irc_server: unescape(fields[8]),
+                url: unescape(fields[9]),
+            })
+        }
+        ReplyCode::NoSuchGroup => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse a MYLIST reply, returning the fid of the entry's file.
+pub fn parse_mylist_fid(reply: &ServerReply) -> Result<u32> {
+    match reply.reply_code() {
+        ReplyCode::Mylist => {
+            let fid = reply.fields().get(1).cloned().unwrap_or("");
+            Ok(fid.parse()?)
+        }
+        ReplyCode::NoSuchEntry => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse a MYLIST reply into the whole entry.
+pub fn parse_mylist(reply: &ServerReply) -> Result<MylistEntry> {
+    match reply.reply_code() {
+        ReplyCode::Mylist => {
+            let fields = reply.fields();
+            let field = |i: usize| fields.get(i).cloned().unwrap_or("");
+            // lid|fid|eid|aid|gid|date|state|viewdate|storage|source|other|filestate
+            let state = match field(6) {
+                "0" => MylistState::Unknown,
+                "1" => MylistState::Internal,
+                "2" => MylistState::External,
+                "3" => MylistState::Deleted,
+                "4" => MylistState::Remote,
+                other => {
+                    return Err(AnidbError::ParseField {
+                        field: "mylist state",
+                        value: other.to_owned(),
+                    })
+                }
+            };
+            let viewdate: u64 = parse_field("viewdate", field(7))?;
+            Ok(MylistEntry {
+                lid: parse_field("lid", field(0))?,
+                fid: parse_field("fid", field(1))?,
+                state,
+                viewdate: if viewdate == 0 { None } else { Some(viewdate) },
+                storage: escape::unescape_field(field(8)),
+            })
+        }
+        ReplyCode::MultipleMylistEntries => {
+            Err(AnidbError::MultipleMylistEntries(reply.fields().join("|")))
+        }
+        ReplyCode::NoSuchEntry => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse a MYLISTADD reply.
+pub fn parse_mylist_add(reply: &ServerReply) -> Result<MylistOutcome> {
+    let lid = || -> Result<u32> {
+        let lid = reply.fields().first().cloned().unwrap_or("");
+        Ok(lid.parse()?)
+    };
+    match reply.reply_code() {
+        ReplyCode::MylistEntryAdded => Ok(MylistOutcome::Added(lid()?)),
+        ReplyCode::FileAlreadyInMylist => Ok(MylistOutcome::AlreadyPresent(lid()?)),
+        ReplyCode::NoSuchFile => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse a VOTE reply. 261 VOTE FOUND becomes `AlreadyVoted`, with the existing value.
+pub fn parse_vote(reply: &ServerReply) -> Result<()> {
+    match reply.reply_code() {
+        ReplyCode::Voted | ReplyCode::VoteUpdated => Ok(()),
+        ReplyCode::VoteFound => {
+            // name|value|type|id
+            let value = reply.fields().get(1).cloned().unwrap_or("");
+            Err(AnidbError::AlreadyVoted(parse_field("vote value", value)?))
+        }
+        ReplyCode::NoSuchVote => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+/// Parse a NOTIFYLIST reply.
+pub fn parse_notify_list(reply: &ServerReply) -> Result<Vec<NotifyRef>> {
+    if reply.reply_code() != ReplyCode::NotifyList {
+        return Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned()));
+    }
+    reply
+        .records()
+        .map(|record| {
+            let typ = record.first().cloned().unwrap_or("");
+            let mut chars = typ.chars();
+            match (chars.next(), chars.next()) {
+                (Some(typ), None) => Ok(NotifyRef {
+                    typ,
+                    id: parse_field("notification id", record.get(1).cloned().unwrap_or(""))?,
+                }),
+                _ => Err(AnidbError::ParseField {
+                    field: "notification type",
+                    value: typ.to_owned(),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Parse a NOTIFYGET reply, which is a message for 292 and new files for 293.
+pub fn parse_notify_get(reply: &ServerReply) -> Result<Notification> {
+    let fields = reply.fields();
+    let field = |i: usize| fields.get(i).cloned().unwrap_or("");
+    match reply.reply_code() {
+        ReplyCode::NotifyGetMessage => Ok(Notification::Message {
+            id: parse_field("message id", field(0))?,
+            from_uid: parse_field("from uid", field(1))?,
+            from_name: escape::unescape_field(field(2)),
+            date: parse_field("date", field(3))?,
+            kind: parse_field("message type", field(4))?,
+            title: escape::unescape_field(field(5)),
+            body: escape::unescape_field(field(6)),
+        }),
+        ReplyCode::NotifyGetNotify => Ok(Notification::NewFiles {
+            aid: parse_field("aid", field(0))?,
+            kind: parse_field("notification type", field(1))?,
+            count: parse_field("count", field(2))?,
+            date: parse_field("date", field(3))?,
+            anime_name: escape::unescape_field(field(4)),
+            fids: field(5)
+                .split(',')
+                .filter(|fid| !fid.is_empty())
+                .map(|fid| parse_field("fid", fid))
+                .collect::<Result<_>>()?,
+        }),
+        ReplyCode::NoSuchNotify => Err(AnidbError::NoSuchFile),
+        _ => Err(AnidbError::ErrorCode(reply.code, reply.data.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod test_parse {
+    use super::*;
+
+    #[test]
+    fn test_reply_code() {
+        assert_eq!(ReplyCode::from_i32(220), ReplyCode::File);
+        assert_eq!(ReplyCode::File.to_i32(), 220);
+        assert_eq!(ReplyCode::from_i32(555), ReplyCode::Banned);
+        assert_eq!(ReplyCode::from_i32(799), ReplyCode::Unknown(799));
+        assert_eq!(ReplyCode::Unknown(799).to_i32(), 799);
+        for code in 100..1000 {
+            assert_eq!(ReplyCode::from_i32(code).to_i32(), code);
+        }
+    }
+
+    #[test]
+    fn test_parse_reply_ok() {
+        let reply = b"500 LOGIN FAILED";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(ret.code, 500);
+        assert_eq!(ret.data, "LOGIN FAILED");
+    }
+
+    #[test]
+    fn test_parse_reply_fail_1() {
+        let reply = b"a3i5LOGIN FAILED";
+        assert_eq!(true, parse_reply(reply, reply.len()).is_err());
+    }
+
+    #[test]
+    fn test_parse_reply_fail_2() {
+        let reply = b"34i5LOGIN FAILED";
+        assert_eq!(true, parse_reply(reply, reply.len()).is_err());
+    }
+
+    #[test]
+    fn test_parse_encoding() {
+        let reply = b"219 ENCODING CHANGED\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_encoding(&reply).is_ok());
+
+        let reply = b"519 ENCODING NOT SUPPORTED\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        match parse_encoding(&reply) {
+            Err(AnidbError::ErrorCode(519, _)) => (),
+            other => panic!("Expected ErrorCode, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_ping() {
+        let reply = b"300 PONG\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_ping(&reply).is_ok());
+
+        let reply = b"505 ILLEGAL INPUT OR ACCESS DENIED\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_ping(&reply).is_err());
+    }
+
+    #[test]
+    fn test_parse_version_and_uptime() {
+        let reply = b"998 VERSION\n0.03.730 (2018-01-01)\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(parse_version(&reply).unwrap(), "0.03.730 (2018-01-01)");
+
+        let reply = b"208 UPTIME\n123456\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(parse_uptime(&reply).unwrap(), Duration::from_millis(123456));
+
+        let reply = b"208 UPTIME\nsoon\n";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_uptime(&reply).is_err());
+    }
+
+    #[test]
+    fn test_parse_encrypt() {
+        let reply = b"209 s4lt ENCRYPTION ENABLED";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(parse_encrypt(&reply).unwrap(), "s4lt");
+
+        let reply = b"309 API PASSWORD NOT DEFINED";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        match parse_encrypt(&reply) {
+            Err(AnidbError::ErrorCode(309, _)) => {}
+            other => panic!("Expected ErrorCode(309), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_reply_compressed() {
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let mut encoder = ZlibEncoder::new(vec![0, 0], Compression::default());
+        encoder.write_all(b"230 ANIME\n1|Cowboy Bebop").unwrap();
+        let reply = encoder.finish().unwrap();
+        let ret = parse_reply(&reply, reply.len()).unwrap();
+        assert_eq!(ret.code, 230);
+        assert_eq!(ret.data, "ANIME\n1|Cowboy Bebop");
+    }
+
+    #[test]
+    fn test_parse_reply_corrupt_compressed() {
+        let reply = b"\0\0not deflate data";
+        assert!(parse_reply(reply, reply.len()).is_err());
+    }
+
+    #[test]
+    fn test_parse_ban() {
+        let reply = b"555 BANNED\nFlooding, banned for 2 hours";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        let (reason, duration) = parse_ban(&reply).unwrap();
+        assert_eq!(reason, "Flooding, banned for 2 hours");
+        assert_eq!(duration, Duration::from_secs(2 * 60 * 60));
+    }
+
+    #[test]
+    fn test_parse_ban_default_duration() {
+        let reply = b"555 BANNED\nLeech";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(
+            parse_ban(&reply),
+            Some(("Leech".to_owned(), DEFAULT_BAN_DURATION))
+        );
+
+        let reply = b"220 FILE\n1";
+        let reply = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(parse_ban(&reply), None);
+    }
+
+    #[test]
+    fn test_parse_tagged_reply() {
+        let reply = b"t12 220 FILE\n1|2";
+        let ret = parse_tagged_reply(reply, reply.len(), Some("t12"), false).unwrap();
+        assert_eq!(ret.code, 220);
+        assert_eq!(ret.data, "FILE\n1|2");
+
+        match parse_tagged_reply(reply, reply.len(), Some("t13"), false) {
+            Err(AnidbError::TagMismatch { ref expected, .. }) => assert_eq!(expected, "t13"),
+            other => panic!("Expected TagMismatch, got {:?}", other),
+        }
+        // "t1" is a prefix of "t12", but isn't the same tag.
+        assert!(parse_tagged_reply(reply, reply.len(), Some("t1"), false).is_err());
+
+        let reply = b"598 UNKNOWN COMMAND";
+        let ret = parse_tagged_reply(reply, reply.len(), Some("t12"), false).unwrap();
+        assert_eq!(ret.code, 598);
+    }
+
+    #[test]
+    fn test_decode_text() {
+        assert_eq!(decode_text("Café".as_bytes(), true).unwrap(), "Café");
+        assert_eq!(decode_text(b"Caf\xe9", false).unwrap(), "Café");
+        match decode_text(b"Caf\xe9", true) {
+            Err(AnidbError::Utf8Error(_)) => (),
+            other => panic!("Expected Utf8Error, got {:?}", other),
+        }
+
+        let reply = b"240 EPISODE\n1|Caf\xe9";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(ret.data, "EPISODE\n1|Café");
+        assert!(parse_tagged_reply(reply, reply.len(), None, true).is_err());
+    }
+
+    #[test]
+    fn test_add_param() {
+        assert_eq!(add_param("PING", "tag", "t1"), "PING tag=t1");
+        assert_eq!(add_param("ANIME aid=1", "s", "abc"), "ANIME aid=1&s=abc");
+    }
+
+    #[test]
+    fn test_parse_reply_too_short() {
+        let reply = b"3D";
+        match parse_reply(reply, reply.len()) {
+            Err(AnidbError::ShortReply(ref short)) => assert_eq!(short, b"3D"),
+            other => panic!("Expected ShortReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_reply_empty() {
+        match parse_reply(b"", 0) {
+            Err(AnidbError::EmptyReply) => (),
+            other => panic!("Expected EmptyReply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_reply_exact_length() {
+        let reply = b"777 O";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(ret.code, 777);
+        assert_eq!(ret.data, "O");
+    }
+
+    #[test]
+    fn test_validate_auth_accepted() {
+        let reply = b"200 abcd1 LOGIN ACCEPTED\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(validate_auth(&ret).unwrap(), "abcd1");
+    }
+
+    #[test]
+    fn test_validate_auth_new_version() {
+        let reply = b"201 abcd1 LOGIN ACCEPTED - NEW VERSION AVAILABLE\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(validate_auth(&ret).unwrap(), "abcd1");
+    }
+
+    #[test]
+    fn test_validate_auth_failed() {
+        let reply = b"500 LOGIN FAILED\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert!(validate_auth(&ret).is_err());
+    }
+
+    #[test]
+    fn test_validate_auth_client_rejected() {
+        let reply = b"503 CLIENT VERSION OUTDATED\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match validate_auth(&ret) {
+            Err(AnidbError::ClientOutdated) => {}
+            other => panic!("Expected ClientOutdated, got {:?}", other),
+        }
+
+        let reply = b"504 CLIENT BANNED - Not registered\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match validate_auth(&ret) {
+            Err(AnidbError::ClientBanned(ref reason)) => assert_eq!(reason, "Not registered"),
+            other => panic!("Expected ClientBanned, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_mylist_add_added() {
+        let reply = b"210 MYLIST ENTRY ADDED\n123456\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let outcome = parse_mylist_add(&ret).unwrap();
+        assert_eq!(outcome, MylistOutcome::Added(123456));
+    }
+
+    #[test]
+    fn test_parse_mylist_add_already_present() {
+        let reply =
+            b"310 FILE ALREADY IN MYLIST\n123456|1879191|183230|12235|10435|1498599583|1|0|||||1\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let outcome = parse_mylist_add(&ret).unwrap();
+        assert_eq!(outcome, MylistOutcome::AlreadyPresent(123456));
+        assert_eq!(outcome.lid(), 123456);
+    }
+
+    #[test]
+    fn test_parse_mylist_add_no_such_file() {
+        let reply = b"320 NO SUCH FILE\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_mylist_add(&ret) {
+            Err(AnidbError::NoSuchFile) => (),
+            other => panic!("Expected NoSuchFile, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_episode() {
+        let reply = "240 EPISODE\n183230|12235|25|802|12|01|A New Beginning|Arata na Hajimari|新たな始まり|1483833600|1\n".as_bytes();
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let episode = parse_episode(&ret).unwrap();
+        assert_eq!(episode.eid, 183230);
+        assert_eq!(episode.rating, 802);
+        assert_eq!(episode.ep_number, "01");
+        assert_eq!(episode.name_kanji, "新たな始まり");
+        assert_eq!(episode.aired, 1483833600);
+    }
+
+    #[test]
+    fn test_parse_episode_invalid_field() {
+        let reply = "240 EPISODE\n183230|12235|25 min|802|12|01|A New Beginning|Arata na Hajimari|新たな始まり|1483833600|1\n".as_bytes();
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_episode(&ret) {
+            Err(AnidbError::ParseField { field, value }) => {
+                assert_eq!(field, "length");
+                assert_eq!(value, "25 min");
+            }
+            other => panic!("Expected ParseField, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_group() {
+        let reply = b"250 GROUP\n10435|812|340|32|760|AnimeSenshi Subs|Asenshi|#asenshi|irc.rizon.net|http://www.asenshi.moe|14335.jpg|0|0\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let group = parse_group(&ret).unwrap();
+        assert_eq!(group.gid, 10435);
+        assert_eq!(group.name, "AnimeSenshi Subs");
+        assert_eq!(group.short_name, "Asenshi");
+        assert_eq!(group.irc_channel, "#asenshi");
+        assert_eq!(group.url, "http://www.asenshi.moe");
+    }
+
+    #[test]
+    fn test_parse_group_invalid_field() {
+        let reply = b"250 GROUP\nasenshi|812|340|32|760|AnimeSenshi Subs|Asenshi|#asenshi|irc.rizon.net|http://www.asenshi.moe|14335.jpg|0|0\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_group(&ret) {
+            Err(AnidbError::ParseField { field, value }) => {
+                assert_eq!(field, "gid");
+                assert_eq!(value, "asenshi");
+            }
+            other => panic!("Expected ParseField, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_mylist_fid() {
+        let reply = b"221 MYLIST\n123456|1879191|183230|12235|10435|1498599583|1|0|||||1\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(parse_mylist_fid(&ret).unwrap(), 1879191);
+    }
+
+    #[test]
+    fn test_parse_mylist() {
+        let reply =
+            b"221 MYLIST\n123456|1879191|183230|12235|10435|1498599583|1|1498600000|shelf 2|||1\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let entry = parse_mylist(&ret).unwrap();
+        assert_eq!(entry.lid, 123456);
+        assert_eq!(entry.fid, 1879191);
+        assert_eq!(entry.state, MylistState::Internal);
+        assert_eq!(entry.viewdate, Some(1498600000));
+        assert_eq!(entry.storage, "shelf 2");
+
+        let reply = b"221 MYLIST\n123456|1879191|183230|12235|10435|1498599583|3|0|||||1\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let entry = parse_mylist(&ret).unwrap();
+        assert_eq!(entry.state, MylistState::Deleted);
+        assert_eq!(entry.viewdate, None);
+    }
+
+    #[test]
+    fn test_parse_vote() {
+        let reply = b"260 VOTED\nLittle Witch Academia (2017)|850|1|12235\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_vote(&ret).is_ok());
+
+        let reply = b"262 VOTE UPDATED\nLittle Witch Academia (2017)|900|1|12235\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_vote(&ret).is_ok());
+
+        let reply = b"261 VOTE FOUND\nLittle Witch Academia (2017)|850|1|12235\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_vote(&ret) {
+            Err(AnidbError::AlreadyVoted(850)) => {}
+            other => panic!("Expected AlreadyVoted(850), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_notify_list() {
+        let reply = b"291 NOTIFYLIST\nM|4567\nN|12235\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert_eq!(
+            parse_notify_list(&ret).unwrap(),
+            vec![
+                NotifyRef { typ: 'M', id: 4567 },
+                NotifyRef {
+                    typ: 'N',
+                    id: 12235
+                },
+            ]
+        );
+
+        let reply = b"291 NOTIFYLIST\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_notify_list(&ret).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_notify_get() {
+        let reply = b"292 NOTIFYGET\n4567|12|someone|1498599583|0|Hello|First line<br />It`s me\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_notify_get(&ret).unwrap() {
+            Notification::Message {
+                id, title, body, ..
+            } => {
+                assert_eq!(id, 4567);
+                assert_eq!(title, "Hello");
+                assert_eq!(body, "First line\nIt's me");
+            }
+            other => panic!("Expected a message, got {:?}", other),
+        }
+
+        let reply =
+            b"293 NOTIFYGET\n12235|1|2|1498599583|Little Witch Academia (2017)|1879191,1879192\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_notify_get(&ret).unwrap() {
+            Notification::NewFiles {
+                aid, count, fids, ..
+            } => {
+                assert_eq!(aid, 12235);
+                assert_eq!(count, 2);
+                assert_eq!(fids, vec![1879191, 1879192]);
+            }
+            other => panic!("Expected new files, got {:?}", other),
+        }
+
+        let reply = b"392 NO SUCH ENTRY\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_notify_get(&ret) {
+            Err(AnidbError::NoSuchFile) => {}
+            other => panic!("Expected NoSuchFile, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_mylist_errors() {
+        let reply = b"321 NO SUCH ENTRY\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_mylist(&ret) {
+            Err(AnidbError::NoSuchFile) => {}
+            other => panic!("Expected NoSuchFile, got {:?}", other),
+        }
+
+        let reply = b"312 MULTIPLE MYLIST ENTRIES\nLittle Witch Academia (2017)|25|1|0|0|0|1|2\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_mylist(&ret) {
+            Err(AnidbError::MultipleMylistEntries(_)) => {}
+            other => panic!("Expected MultipleMylistEntries, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_file_error_code() {
+        let reply = b"598 UNKNOWN COMMAND\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_file(&ret, &FileMask::default(), None) {
+            Err(AnidbError::ErrorCode(598, _)) => (),
+            other => panic!("Expected ErrorCode, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_file_multiple() {
+        let reply = b"322 MULTIPLE FILES FOUND\n1879191|1879192|1880000\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_file(&ret, &FileMask::default(), None) {
+            Err(AnidbError::MultipleFiles(fids)) => {
+                assert_eq!(fids, vec![1879191, 1879192, 1880000])
+            }
+            other => panic!("Expected MultipleFiles, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_file_escaped_filename() {
+        let reply = b"220 FILE\n1879191|12235|183230|10435||5|Tom%20%26%20Jerry%20-%2001.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)|||lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let file = parse_file(&ret, &FileMask::default(), None).unwrap();
+        assert_eq!(file.filename.unwrap(), "Tom & Jerry - 01.mkv");
+    }
+
+    #[test]
+    fn test_parse_file_escaped_text() {
+        let reply = b"220 FILE\n1879191|12235|183230|10435||5|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)||Akko`s Story'Shiny Chariot|lwatv|01|A New Beginning<br />Part One|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let file = parse_file(&ret, &FileMask::default(), None).unwrap();
+        assert_eq!(file.ep_name.unwrap(), "A New Beginning\nPart One");
+        assert_eq!(file.series_other, vec!["Akko's Story", "Shiny Chariot"]);
+    }
+
+    #[test]
+    fn test_parse_file_series_other() {
+        let reply = "220 FILE\n1879191|12235|183230|10435||5|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)||リトルウィッチアカデミア (2017)'小魔女学园 (2017)|lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n".as_bytes();
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let file = parse_file(&ret, &FileMask::default(), None).unwrap();
+        assert_eq!(
+            file.series_other,
+            vec!["リトルウィッチアカデミア (2017)", "小魔女学园 (2017)"]
+        );
+    }
+
+    #[test]
+    fn test_parse_file_other_episodes() {
+        let reply = b"220 FILE\n1879191|12235|183230|10435|183231,100'183232,30|5|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)|||lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let file = parse_file(&ret, &FileMask::default(), None).unwrap();
+        assert_eq!(file.other_episodes, vec![(183231, 100), (183232, 30)]);
+    }
+
+    #[test]
+    fn test_parse_anime() {
+        let reply = b"230 ANIME\n12235|2017-2017|TV Series|Little Witch Academia (2017)|Little Witch Academia (2017)|25|1483833600\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let anime = parse_anime(&ret).unwrap();
+        assert_eq!(anime.aid, 12235);
+        assert_eq!(anime.typ, "TV Series");
+        assert_eq!(anime.romaji_name, "Little Witch Academia (2017)");
+        assert_eq!(anime.episode_count, 25);
+        assert_eq!(anime.air_date, 1483833600);
+    }
+
+    #[test]
+    fn test_parse_anime_escaped_name() {
+        let reply = b"230 ANIME\n1|1998-1998|TV Series|Cowboy Bebop: Tengoku no Tobira|Cowboy Bebop: Knockin` on Heaven`s Door|1|0\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let anime = parse_anime(&ret).unwrap();
+        assert_eq!(
+            anime.english_name,
+            "Cowboy Bebop: Knockin' on Heaven's Door"
+        );
+    }
+
+    #[test]
+    fn test_parse_anime_invalid_field() {
+        let reply = b"230 ANIME\n12235|2017-2017|TV Series|Little Witch Academia (2017)|Little Witch Academia (2017)|many|1483833600\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_anime(&ret) {
+            Err(AnidbError::ParseField { field, value }) => {
+                assert_eq!(field, "episode_count");
+                assert_eq!(value, "many");
+            }
+            other => panic!("Expected ParseField, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_anime_no_such_anime() {
+        let reply = b"330 NO SUCH ANIME\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_anime(&ret) {
+            Err(AnidbError::NoSuchFile) => (),
+            other => panic!("Expected NoSuchFile, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_file_short_reply() {
+        let reply = b"220 FILE\n1879191|12235|183230\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_file(&ret, &FileMask::default(), None) {
+            Err(AnidbError::ParseField { field, .. }) => assert_eq!(field, "gid"),
+            other => panic!("Expected ParseField, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_file_invalid_field() {
+        let reply = b"220 FILE\n1879191|abc|183230|10435||5|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)|||lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        match parse_file(&ret, &FileMask::default(), None) {
+            Err(AnidbError::ParseField { field, value }) => {
+                assert_eq!(field, "aid");
+                assert_eq!(value, "abc");
+            }
+            other => panic!("Expected ParseField, got {:?}", other),
+        }
+    }
+
+    fn echo_mask() -> FileMask {
+        FileMask {
+            size: true,
+            ed2k: true,
+            ..FileMask::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_file_partial_mask() {
+        let reply = b"220 FILE\n1879191|12235|lwa.mkv|Little Witch Academia (2017)\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let mask = FileMask {
+            aid: true,
+            filename: true,
+            series_romaji: true,
+            ..FileMask::none()
+        };
+        let file = parse_file(&ret, &mask, None).unwrap();
+        assert_eq!(file.fid, 1879191);
+        assert_eq!(file.aid, 12235);
+        assert_eq!(file.eid, 0);
+        assert_eq!(file.filename.unwrap(), "lwa.mkv");
+        assert_eq!(file.series_romaji.unwrap(), "Little Witch Academia (2017)");
+        assert_eq!(file.ep_name, None);
+    }
+
+    #[test]
+    fn test_parse_file_unknown_fields() {
+        let reply = b"220 FILE\n1879191|12235|183230|10435||5|lwa.mkv|0||2017|TV Series|Little Witch Academia (2017)||||01||||\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let file = parse_file(&ret, &FileMask::default(), None).unwrap();
+        assert_eq!(file.total_eps, None);
+        assert_eq!(file.highest_ep, None);
+        assert_eq!(file.series_english, None);
+        assert_eq!(file.ep_number.unwrap(), "01");
+        assert_eq!(file.ep_name, None);
+        assert_eq!(file.group_short, None);
+        assert!(file.series_other.is_empty());
+    }
+
+    fn echo_hash() -> Ed2kHash {
+        Ed2kHash {
+            bin: [0; 16],
+            size: 365985588,
+            hex: "a5a3c7e9d2bd7b5f8e8e1c7c3a45e6b1".to_owned(),
+            alt_bin: None,
+            crc32: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_file_echo_ok() {
+        let reply = b"220 FILE\n1879191|12235|183230|10435||5|365985588|A5A3C7E9D2BD7B5F8E8E1C7C3A45E6B1|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)|||lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        let file = parse_file(&ret, &echo_mask(), Some(&echo_hash())).unwrap();
+        assert_eq!(file.fid, 1879191);
+        assert_eq!(file.filename.unwrap(), "lwa.mkv");
+    }
+
+    #[test]
+    fn test_parse_file_echo_mismatch() {
+        let reply = b"220 FILE\n1879191|12235|183230|10435||5|365985588|ffffffffffffffffffffffffffffffff|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)|||lwatv|01|A New Beginning|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+        let ret = parse_reply(reply, reply.len()).unwrap();
+        assert!(parse_file(&ret, &echo_mask(), Some(&echo_hash())).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_format {
+    use super::*;
+
+    #[test]
+    fn test_format_login_string() {
+        let login_string = format_login("leeloo_dallas", "multipass", "anidbrs", 1);
+        assert_eq!(
+            login_string,
+            "AUTH user=leeloo_dallas&pass=multipass&protover=3&client=anidbrs&clientver=1"
+        );
+    }
+
+    #[test]
+    fn test_format_encrypt_string() {
+        assert_eq!(
+            format_encrypt("leeloo_dallas"),
+            "ENCRYPT user=leeloo_dallas&type=1"
+        );
+    }
+
+    #[test]
+    fn test_format_logout_string() {
+        let logout_str = format_logout("abcd1234");
+        assert_eq!(logout_str, "LOGOUT s=abcd1234");
+    }
+
+    #[test]
+    fn test_format_file_fid_str() {
+        assert_eq!(
+            format_file_fid(1879191, &FileMask::default()),
+            "FILE fid=1879191&fmask=7500000100&amask=F0B8E0C0"
+        );
+    }
+
+    #[test]
+    fn test_format_file_episode_str() {
+        assert_eq!(
+            format_file_episode(12235, 10435, "1", &FileMask::default()),
+            "FILE aid=12235&gid=10435&epno=1&fmask=7500000100&amask=F0B8E0C0"
+        );
+    }
+
+    #[test]
+    fn test_format_file_hash_str_echo() {
+        let hash = Ed2kHash {
+            bin: [0; 16],
+            size: 1234,
+            hex: "00000000000000000000000000000000".to_owned(),
+            alt_bin: None,
+            crc32: None,
+        };
+        let mask = FileMask {
+            size: true,
+            ed2k: true,
+            ..FileMask::default()
+        };
+        let file_str = format_file(&hash, &mask);
+        assert_eq!(
+            file_str,
+            "FILE size=1234&ed2k=00000000000000000000000000000000&fmask=75C0000100&amask=F0B8E0C0"
+        );
+    }
+
+    #[test]
+    fn test_file_mask_default() {
+        let mask = FileMask::default();
+        assert_eq!(mask.fmask(), "7500000100");
+        assert_eq!(mask.amask(), "F0B8E0C0");
+        assert_eq!(FileMask::none().fmask(), "0000000000");
+    }
+
+    #[test]
+    fn test_format_mylist_add_str() {
+        let hash = Ed2kHash {
+            bin: [0; 16],
+            size: 1234,
+            hex: "00000000000000000000000000000000".to_owned(),
+            alt_bin: None,
+            crc32: None,
+        };
+        let mylist_str = format_mylist_add(&hash, MylistState::Internal, true);
+        assert_eq!(
+            mylist_str,
+            "MYLISTADD size=1234&ed2k=00000000000000000000000000000000&state=1&viewed=1"
+        );
+    }
+
+    #[test]
+    fn test_format_vote_str() {
+        assert_eq!(
+            format_vote(VoteType::Anime, 3651, 850),
+            "VOTE type=1&id=3651&value=850"
+        );
+        assert_eq!(
+            format_vote(VoteType::Group, 1, 700),
+            "VOTE type=3&id=1&value=700"
+        );
+        assert_eq!(
+            format_vote(VoteType::Episode { epno: 12 }, 3651, 1000),
+            "VOTE type=1&id=3651&value=1000&epno=12"
+        );
+    }
+}
@@ -2,8 +2,11 @@ extern crate anidb;
 
 mod mock_server;
 
-use anidb::Anidb;
-use mock_server::MockServer;
+use anidb::ed2k::Ed2kHash;
+use anidb::{protocol, Anidb, AnidbError, Cache, MemoryCache, Session};
+use mock_server::{MockServer, MISSING_HASH};
+use std::env;
+use std::path::PathBuf;
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -15,8 +18,13 @@ fn setup(port: u16) {
     });
 }
 
+fn cache_dir(port: u16) -> PathBuf {
+    env::temp_dir().join(format!("anidb-rs-test-{}", port))
+}
+
 fn login_logout(mut db: Anidb) {
     db.login("foo", "bar").expect("Login failed");
+    db.connect().expect("Connect failed");
     db.logout().expect("Logout failed");
 }
 
@@ -25,7 +33,7 @@ fn it_works() {
     let port = 4444u16;
     setup(port);
 
-    let mut db = Anidb::new(("127.0.0.1", port)).unwrap();
+    let mut db = Anidb::new(("127.0.0.1", port), &cache_dir(port)).unwrap();
     db.ratelimit = Duration::from_secs(0);
     login_logout(db);
 }
@@ -35,9 +43,129 @@ fn ratelimit_works() {
     let port = 4445u16;
     setup(port);
 
-    let db = Anidb::new(("127.0.0.1", port)).unwrap();
+    // The rate limit counts from construction, so start the clock before that.
     let before = Instant::now();
+    let db = Anidb::new(("127.0.0.1", port), &cache_dir(port)).unwrap();
     login_logout(db);
     let after = Instant::now();
     assert!(after.duration_since(before) >= Duration::from_secs(8));
 }
+
+#[test]
+fn connect_reuses_session() {
+    let port = 4446u16;
+    setup(port);
+
+    let mut db = Anidb::new(("127.0.0.1", port), &cache_dir(port)).unwrap();
+    db.ratelimit = Duration::from_secs(0);
+    db.login("foo", "bar").expect("Login failed");
+    assert!(!db.is_connected());
+    let first = db.connect().expect("Connect failed");
+    assert!(!first.reused);
+    assert!(db.is_connected());
+    assert_eq!(db.session_id(), Some(first.session_id.as_str()));
+    let second = db.connect().expect("Connect failed");
+    assert!(second.reused);
+    assert_eq!(first.session_id, second.session_id);
+    db.logout().expect("Logout failed");
+    assert!(!db.is_connected());
+    assert_eq!(db.session_id(), None);
+}
+
+#[test]
+fn with_cache_works() {
+    let port = 4447u16;
+    setup(port);
+
+    let cache = Cache::new(&cache_dir(port)).expect("Cache creation failed");
+    let mut db = Anidb::with_cache(("127.0.0.1", port), cache).unwrap();
+    db.ratelimit = Duration::from_secs(0);
+    login_logout(db);
+}
+
+#[test]
+fn memory_cache_works() {
+    let port = 4450u16;
+    setup(port);
+
+    let mut db = Anidb::with_cache(("127.0.0.1", port), MemoryCache::new()).unwrap();
+    db.ratelimit = Duration::from_secs(0);
+    login_logout(db);
+}
+
+#[test]
+fn logout_login_switches_user() {
+    let port = 4448u16;
+    setup(port);
+
+    let mut db = Anidb::new(("127.0.0.1", port), &cache_dir(port)).unwrap();
+    db.ratelimit = Duration::from_secs(0);
+    // Make sure the commands really reach the server.
+    for aid in &[1, 2] {
+        db.cache.invalidate(&protocol::format_anime(*aid)).unwrap();
+    }
+
+    db.login("alice", "foo").expect("Login failed");
+    let first = db.connect().expect("Connect failed").session_id;
+    assert_eq!(db.anime_from_aid(1).unwrap().romaji_name, "alice");
+    db.logout().expect("Logout failed");
+
+    db.login("bob", "bar").expect("Login failed");
+    assert_eq!(db.anime_from_aid(2).unwrap().romaji_name, "bob");
+    match db.session {
+        Session::Connected(ref second) => assert!(*second != first),
+        _ => panic!("Expected a new session"),
+    }
+}
+
+#[test]
+fn ping_needs_no_session() {
+    let port = 4449u16;
+    setup(port);
+
+    let mut db = Anidb::new(("127.0.0.1", port), &cache_dir(port)).unwrap();
+    db.ratelimit = Duration::from_secs(0);
+    db.ping().expect("Ping failed");
+    match db.session {
+        Session::Disconnected => (),
+        ref other => panic!("Expected no login, got {:?}", other),
+    }
+}
+
+#[test]
+fn file_from_hash_works() {
+    let port = 4451u16;
+    setup(port);
+
+    let mut db = Anidb::with_cache(("127.0.0.1", port), MemoryCache::new()).unwrap();
+    db.ratelimit = Duration::from_secs(0);
+    db.login("foo", "bar").expect("Login failed");
+
+    let link = "ed2k://|file|lwa.mkv|365985588|a5a3c7e9d2bd7b5f8e8e1c7c3a45e6b1|/";
+    let hash = Ed2kHash::from_ed2k_link(link).unwrap();
+    let file = db.file_from_hash(&hash).expect("FILE failed");
+    assert_eq!(file.fid, 1879191);
+    assert_eq!(file.aid, 12235);
+    assert_eq!(file.eid, 183230);
+    assert_eq!(file.gid, 10435);
+    assert_eq!(file.filename.unwrap(), "lwa.mkv");
+    assert_eq!(file.total_eps, Some(25));
+    assert_eq!(file.year.unwrap(), "2017-2017");
+    assert_eq!(file.series_romaji.unwrap(), "Little Witch Academia (2017)");
+    assert_eq!(file.series_english, None);
+    assert_eq!(
+        file.series_other,
+        vec!["リトルウィッチアカデミア (2017)", "小魔女学园 (2017)"]
+    );
+    assert_eq!(file.ep_number.unwrap(), "01");
+    assert_eq!(file.ep_name.unwrap(), "A New Beginning\nPart One");
+    assert_eq!(file.group_short.unwrap(), "Asenshi");
+
+    let link = format!("ed2k://|file|missing.mkv|1000|{}|/", MISSING_HASH);
+    let missing = Ed2kHash::from_ed2k_link(&link).unwrap();
+    match db.file_from_hash(&missing) {
+        Err(AnidbError::NoSuchFile) => (),
+        other => panic!("Expected NoSuchFile, got {:?}", other),
+    }
+    db.logout().expect("Logout failed");
+}
@@ -2,16 +2,36 @@ extern crate anidb;
 extern crate rand;
 
 use self::rand::Rng;
+use std::collections::HashMap;
 use std::net::UdpSocket;
 use std::str;
 
 use anidb::Result;
 
+/// The hash FILE answers `320 NO SUCH FILE` for. Any other hash gets `FILE_REPLY`.
+pub const MISSING_HASH: &str = "00000000000000000000000000000000";
+
+/// A FILE reply for the default mask: Little Witch Academia (2017) episode 1.
+pub const FILE_REPLY: &str = "220 FILE\n1879191|12235|183230|10435||5|lwa.mkv|25|25|2017-2017|TV Series|Little Witch Academia (2017)||リトルウィッチアカデミア (2017)'小魔女学园 (2017)|lwatv|01|A New Beginning<br />Part One|Arata na Hajimari|AnimeSenshi Subs|Asenshi\n";
+
 pub struct MockServer {
     pub socket: UdpSocket,
     pub token: String,
 }
 
+/// The value of a `name=value` parameter in a request.
+fn param<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    let params = &request[request.find(' ')? + 1..];
+    params.trim().split('&').find_map(|param| {
+        let eq = param.find('=')?;
+        if &param[..eq] == name {
+            Some(&param[eq + 1..])
+        } else {
+            None
+        }
+    })
+}
+
 impl MockServer {
     pub fn new(port: u16) -> Result<MockServer> {
         let socket = UdpSocket::bind(("0.0.0.0", port))?;
@@ -21,15 +41,67 @@ impl MockServer {
         })
     }
 
+    /// Answer a request. `sessions` maps session keys to the user that logged in with them,
+    /// and `logins` counts logins, to keep session keys unique.
+    ///
+    /// ANIME replies use the user's name as the anime's name, so tests can tell whose session
+    /// a command ran under. FILE replies are always `FILE_REPLY`, except for `MISSING_HASH`.
+    fn reply(
+        &self,
+        request: &str,
+        sessions: &mut HashMap<String, String>,
+        logins: &mut u32,
+    ) -> String {
+        if request.starts_with("AUTH ") {
+            let user = param(request, "user").unwrap_or("").to_owned();
+            let session = format!("{}{}", self.token, logins);
+            *logins += 1;
+            sessions.insert(session.clone(), user);
+            return format!("200 {} LOGIN ACCEPTED\n", session);
+        }
+        if request.starts_with("PING") {
+            return "300 PONG\n".to_owned();
+        }
+        let session = param(request, "s").unwrap_or("");
+        if request.starts_with("LOGOUT ") {
+            sessions.remove(session);
+            return "203 LOGGED OUT\n".to_owned();
+        }
+        match sessions.get(session) {
+            None => "506 INVALID SESSION\n".to_owned(),
+            Some(user) if request.starts_with("ANIME ") => format!(
+                "230 ANIME\n{}|2017-2017|TV Series|{}|{}|25|0\n",
+                param(request, "aid").unwrap_or("0"),
+                user,
+                user
+            ),
+            Some(_) if request.starts_with("ENCODING ") => "219 ENCODING CHANGED\n".to_owned(),
+            Some(_) if request.starts_with("FILE ") => {
+                if param(request, "ed2k") == Some(MISSING_HASH) {
+                    "320 NO SUCH FILE\n".to_owned()
+                } else {
+                    FILE_REPLY.to_owned()
+                }
+            }
+            Some(_) => "598 UNKNOWN COMMAND\n".to_owned(),
+        }
+    }
+
     pub fn update(&self) {
         let mut buf = [0; 2048];
+        let mut sessions = HashMap::new();
+        let mut logins = 0;
         loop {
             match self.socket.recv_from(&mut buf) {
                 Ok((amt, src)) => {
                     println!("amt: {}", amt);
                     println!("src: {}", src);
-                    println!("{}", str::from_utf8(&buf).unwrap_or(""));
-                    let message = format!("200 {} LOGIN ACCEPTED\n", self.token);
+                    let request = str::from_utf8(&buf[..amt]).unwrap_or("");
+                    println!("{}", request);
+                    let mut message = self.reply(request, &mut sessions, &mut logins);
+                    if let Some(tag) = param(request, "tag") {
+                        message = format!("{} {}", tag, message);
+                    }
                     println!("reply: {}", message);
                     self.socket.connect(src).unwrap();
                     self.socket.send(message.as_bytes()).unwrap();